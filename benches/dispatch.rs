@@ -0,0 +1,48 @@
+use nes_emulator::cpu::CPU;
+use nes_emulator::rom::Rom;
+use std::time::Instant;
+
+const INSTRUCTIONS: usize = 20_000_000;
+
+// NROM cartridge whose reset vector points at a loop mixing loads, stores, arithmetic,
+// shifts, branches and jumps:
+//   LDX #$00
+//   LDA $0200,X
+//   ADC #$01
+//   ASL A
+//   STA $0200,X
+//   INX
+//   BNE $8002
+//   JMP $8000
+fn bench_rom() -> Rom {
+  let program = [
+    0xa2, 0x00, 0xbd, 0x00, 0x02, 0x69, 0x01, 0x0a, 0x9d, 0x00, 0x02, 0xe8, 0xd0, 0xf4, 0x4c, 0x00, 0x80,
+  ];
+  let mut prg_rom = vec![0xea; 0x8000];
+  prg_rom[..program.len()].copy_from_slice(&program);
+  prg_rom[0x7ffc] = 0x00;
+  prg_rom[0x7ffd] = 0x80;
+
+  let mut raw = vec![0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+  raw.extend_from_slice(&prg_rom);
+  raw.extend_from_slice(&[0; 0x2000]);
+  Rom::new(&raw).unwrap()
+}
+
+fn main() {
+  let mut cpu = CPU::new(bench_rom());
+  cpu.power_on();
+
+  let start = Instant::now();
+  for _ in 0..INSTRUCTIONS {
+    cpu.step_instruction();
+  }
+  let elapsed = start.elapsed();
+
+  println!(
+    "{} instructions in {:.2?}: {:.1}M instructions per second",
+    INSTRUCTIONS,
+    elapsed,
+    INSTRUCTIONS as f64 / elapsed.as_secs_f64() / 1_000_000.0
+  );
+}
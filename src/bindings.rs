@@ -0,0 +1,270 @@
+use crate::frontend::Command;
+use crate::joypad::JoypadButton;
+
+// Turbo buttons are pressed for this many frames, then released for as many
+const TURBO_PERIOD: usize = 2;
+
+// One binding per line: <player> <button> <key|pad> <input name>, or
+// hotkey <command> <key|pad> <input name>, with pad hotkeys read from the first pad.
+// Key names are SDL key names, pad names are SDL game controller button names
+pub const DEFAULT_BINDINGS: &str = "\
+hotkey quit key Escape
+hotkey pause key Pause
+hotkey frame_advance key \\
+hotkey fast_forward key Tab
+hotkey rewind key Backspace
+hotkey reset key F9
+hotkey power key F10
+hotkey save1 key F1
+hotkey save2 key F2
+hotkey save3 key F3
+hotkey save4 key F4
+hotkey load1 key F5
+hotkey load2 key F6
+hotkey load3 key F7
+hotkey load4 key F8
+1 up key Up
+1 down key Down
+1 left key Left
+1 right key Right
+1 select key Space
+1 start key Return
+1 a key A
+1 b key S
+1 turbo_a key D
+1 turbo_b key F
+2 up key I
+2 down key K
+2 left key J
+2 right key L
+2 select key U
+2 start key O
+2 a key N
+2 b key M
+";
+
+// Pad bindings shared by every player, each player gets the pad connected in their position.
+// Face buttons follow the NES layout with B on the bottom and A to its right
+const DEFAULT_PAD_BINDINGS: [(&str, &str); 10] = [
+  ("up", "dpup"),
+  ("down", "dpdown"),
+  ("left", "dpleft"),
+  ("right", "dpright"),
+  ("select", "back"),
+  ("start", "start"),
+  ("a", "b"),
+  ("b", "a"),
+  ("turbo_a", "y"),
+  ("turbo_b", "x"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+  Button(JoypadButton),
+  Turbo(JoypadButton),
+  Command(Command),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+  Key(String),
+  Pad(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+  // 0 based, player 1 is 0, hotkeys belong to player 1
+  pub player: usize,
+  pub input: Input,
+  pub action: Action,
+}
+
+fn parse_action(name: &str) -> Result<Action, String> {
+  match name {
+    "up" => Ok(Action::Button(JoypadButton::UP)),
+    "down" => Ok(Action::Button(JoypadButton::DOWN)),
+    "left" => Ok(Action::Button(JoypadButton::LEFT)),
+    "right" => Ok(Action::Button(JoypadButton::RIGHT)),
+    "select" => Ok(Action::Button(JoypadButton::SELECT)),
+    "start" => Ok(Action::Button(JoypadButton::START)),
+    "a" => Ok(Action::Button(JoypadButton::BUTTON_A)),
+    "b" => Ok(Action::Button(JoypadButton::BUTTON_B)),
+    "turbo_a" => Ok(Action::Turbo(JoypadButton::BUTTON_A)),
+    "turbo_b" => Ok(Action::Turbo(JoypadButton::BUTTON_B)),
+    _ => Err(format!("Unknown button: {}", name)),
+  }
+}
+
+pub fn parse(text: &str) -> Result<Vec<Binding>, String> {
+  let mut bindings = vec![];
+  for (i, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    let fields: Vec<&str> = line.splitn(4, ' ').collect();
+    if fields.len() != 4 {
+      return Err(format!("Invalid binding on line {}", i + 1));
+    }
+    let (player, action) = match (fields[0], fields[0].parse::<usize>()) {
+      ("hotkey", _) => (0, Action::Command(Command::from_name(fields[1])?)),
+      (_, Ok(player)) if (1..=4).contains(&player) => (player - 1, parse_action(fields[1])?),
+      _ => return Err(format!("Invalid player on line {}", i + 1)),
+    };
+    let name = fields[3].trim().to_string();
+    let input = match fields[2] {
+      "key" => Input::Key(name),
+      "pad" => Input::Pad(name),
+      device => return Err(format!("Unknown input device: {}", device)),
+    };
+    bindings.push(Binding { player, input, action });
+  }
+  Ok(bindings)
+}
+
+pub fn default_bindings() -> Vec<Binding> {
+  let mut bindings = parse(DEFAULT_BINDINGS).unwrap();
+  for player in 0..4 {
+    for (action, button) in DEFAULT_PAD_BINDINGS.iter() {
+      bindings.push(Binding {
+        player,
+        input: Input::Pad(button.to_string()),
+        action: parse_action(action).unwrap(),
+      });
+    }
+  }
+  bindings
+}
+
+// Where a press came from, so an unplugged pad can let go of everything it held
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+  Key,
+  Pad,
+}
+
+// Inputs holding each button bit, for each source
+type PressCounts = [[u8; 8]; 2];
+
+fn count_press(counts: &mut PressCounts, source: Source, button: JoypadButton, pressed: bool) {
+  for (bit, count) in counts[source as usize].iter_mut().enumerate() {
+    if button.bits() & (1 << bit) != 0 {
+      *count = if pressed { count.saturating_add(1) } else { count.saturating_sub(1) };
+    }
+  }
+}
+
+fn pressed_buttons(counts: &PressCounts) -> JoypadButton {
+  let bits = (0..8)
+    .filter(|bit| counts.iter().any(|source| source[*bit] > 0))
+    .fold(0u8, |bits, bit| bits | (1 << bit));
+  JoypadButton::from_bits_truncate(bits)
+}
+
+// Buttons held by one player, merged from every bound input. Presses are counted, so a button
+// bound to several inputs stays down until all of them are released
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlayerInput {
+  held: PressCounts,
+  turbo: PressCounts,
+}
+
+impl PlayerInput {
+  pub fn set(&mut self, source: Source, action: Action, pressed: bool) {
+    match action {
+      Action::Button(button) => count_press(&mut self.held, source, button, pressed),
+      Action::Turbo(button) => count_press(&mut self.turbo, source, button, pressed),
+      Action::Command(_) => {},
+    }
+  }
+
+  pub fn release(&mut self, source: Source) {
+    self.held[source as usize] = [0; 8];
+    self.turbo[source as usize] = [0; 8];
+  }
+
+  pub fn buttons(&self, frame: usize) -> JoypadButton {
+    if (frame / TURBO_PERIOD).is_multiple_of(2) {
+      pressed_buttons(&self.held) | pressed_buttons(&self.turbo)
+    } else {
+      pressed_buttons(&self.held)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let bindings = parse("# comment\n\n2 turbo_b key Left Shift\n1 start pad start\nhotkey save2 key F2\n").unwrap();
+    assert_eq!(
+      bindings,
+      vec![
+        Binding {
+          player: 1,
+          input: Input::Key("Left Shift".to_string()),
+          action: Action::Turbo(JoypadButton::BUTTON_B),
+        },
+        Binding {
+          player: 0,
+          input: Input::Pad("start".to_string()),
+          action: Action::Button(JoypadButton::START),
+        },
+        Binding {
+          player: 0,
+          input: Input::Key("F2".to_string()),
+          action: Action::Command(Command::SaveState(2)),
+        },
+      ]
+    );
+
+    assert_eq!(parse("5 a key A"), Err("Invalid player on line 1".to_string()));
+    assert_eq!(parse("1 c key A"), Err("Unknown button: c".to_string()));
+    assert_eq!(parse("1 a mouse left"), Err("Unknown input device: mouse".to_string()));
+    assert_eq!(parse("1 a key"), Err("Invalid binding on line 1".to_string()));
+    assert_eq!(parse("hotkey a key A"), Err("Unknown command: a".to_string()));
+    assert!(default_bindings().contains(&Binding {
+      player: 0,
+      input: Input::Key("\\".to_string()),
+      action: Action::Command(Command::FrameAdvance),
+    }));
+  }
+
+  #[test]
+  fn test_turbo() {
+    let mut input = PlayerInput::default();
+    input.set(Source::Key, Action::Button(JoypadButton::UP), true);
+    input.set(Source::Key, Action::Turbo(JoypadButton::BUTTON_A), true);
+
+    let frames: Vec<JoypadButton> = (0..5).map(|frame| input.buttons(frame)).collect();
+    let up_a = JoypadButton::UP | JoypadButton::BUTTON_A;
+    assert_eq!(frames, vec![up_a, up_a, JoypadButton::UP, JoypadButton::UP, up_a]);
+
+    input.set(Source::Key, Action::Turbo(JoypadButton::BUTTON_A), false);
+    assert_eq!(input.buttons(0), JoypadButton::UP);
+  }
+
+  #[test]
+  fn test_merged_inputs() {
+    let mut input = PlayerInput::default();
+    let a = Action::Button(JoypadButton::BUTTON_A);
+    input.set(Source::Key, a, true);
+    input.set(Source::Pad, a, true);
+    input.set(Source::Key, a, false);
+    assert_eq!(input.buttons(0), JoypadButton::BUTTON_A);
+    input.set(Source::Pad, a, false);
+    assert_eq!(input.buttons(0), JoypadButton::empty());
+
+    // Two keys on one button, and an unplugged pad letting go of its buttons
+    input.set(Source::Key, a, true);
+    input.set(Source::Key, a, true);
+    input.set(Source::Key, a, false);
+    input.set(Source::Pad, Action::Button(JoypadButton::START), true);
+    assert_eq!(input.buttons(0), JoypadButton::BUTTON_A | JoypadButton::START);
+    input.release(Source::Pad);
+    assert_eq!(input.buttons(0), JoypadButton::BUTTON_A);
+  }
+}
@@ -1,7 +1,41 @@
 use crate::cpu::Mem;
+use crate::cpu::CpuBus;
 use crate::rom::Rom;
 use crate::ppu::PPU;
-use crate::joypad::Joypad;
+use crate::region::Region;
+use crate::controller::Controllers;
+use crate::savestate;
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+use rand::Rng;
+
+// Contents of RAM at power-on, real consoles come up with mostly random values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamPattern {
+  Zeros,
+  Ones,
+  Random,
+}
+
+impl RamPattern {
+  pub fn from_name(name: &str) -> Result<RamPattern, String> {
+    match name {
+      "zeros" => Ok(RamPattern::Zeros),
+      "ones" => Ok(RamPattern::Ones),
+      "random" => Ok(RamPattern::Random),
+      _ => Err(format!("Unknown RAM pattern: {}", name)),
+    }
+  }
+
+  pub fn fill(self, ram: &mut [u8]) {
+    match self {
+      RamPattern::Zeros => ram.iter_mut().for_each(|byte| *byte = 0),
+      RamPattern::Ones => ram.iter_mut().for_each(|byte| *byte = 0xff),
+      RamPattern::Random => rand::thread_rng().fill(ram),
+    }
+  }
+}
 
 pub struct Bus<'call> {
   cpu_vram: [u8; 2048],
@@ -9,9 +43,13 @@ pub struct Bus<'call> {
   // TODO: Remove this
   // program_counter: [u8; 2],
   ppu: PPU,
-  joypad: Joypad,
+  controllers: Controllers,
+  ram_pattern: RamPattern,
+  region: Region,
+  // PPU dots owed to the CPU cycles so far, in units of a whole dot divided by the region's ratio
+  dot_remainder: usize,
   cycles: usize,
-  gameloop_callback: Box<dyn FnMut(&PPU, &mut Joypad) + 'call>,
+  gameloop_callback: Box<dyn FnMut(&PPU, &mut Controllers) + 'call>,
 }
 
 const RAM: u16 = 0x0000;
@@ -21,6 +59,8 @@ const PRG_ROM_MAP: u16 = 0x8000;
 const PRG_ROM_MAP_END: u16 = 0xFFFF;
 const RAM_MIRROR_MASK: u16 = 0b0000_0111_1111_1111; // 0x0 - 0x7FF
 const PPU_MIRROR_MASK: u16 = 0b0010_0000_0000_0111; // 0x2000 - 0x2007
+// Controller ports only drive the low bits, the rest keep the high byte of the address left on the bus
+const CONTROLLER_OPEN_BUS: u8 = 0x40;
 // const PROGRAM_COUNTER_LO: u16 = 0xFFFC;
 // const PROGRAM_COUNTER_HI: u16 = 0xFFFD;
 
@@ -39,7 +79,11 @@ impl<'a> Mem for Bus<'a> {
       0x2004 => self.ppu.read_oam_data(),
       0x2007 => self.ppu.read_data(),
 
-      0x4016 => self.joypad.read(),
+      0x4016 | 0x4017 => {
+        let port = (addr - 0x4016) as usize;
+        self.controllers.sense_light(port, &self.ppu);
+        CONTROLLER_OPEN_BUS | self.controllers.read(port)
+      },
 
       0x2008 ..= PPU_REGISTERS_MIRRORS_END => {
         let mirror_down_addr = addr & PPU_MIRROR_MASK;
@@ -55,6 +99,28 @@ impl<'a> Mem for Bus<'a> {
     }
   }
 
+  fn mem_peek(&self, addr: u16) -> u8 {
+    match addr {
+      RAM ..= RAM_MIRRORS_END => {
+        let mirror_down_addr = addr & RAM_MIRROR_MASK;
+        self.cpu_vram[mirror_down_addr as usize]
+      },
+      0x2002 => self.ppu.peek_status(),
+      0x2004 => self.ppu.read_oam_data(),
+      0x2007 => self.ppu.peek_data(),
+
+      0x4016 => CONTROLLER_OPEN_BUS | self.controllers.peek(0),
+      0x4017 => CONTROLLER_OPEN_BUS | self.controllers.peek(1),
+
+      0x2008 ..= PPU_REGISTERS_MIRRORS_END => {
+        let mirror_down_addr = addr & PPU_MIRROR_MASK;
+        self.mem_peek(mirror_down_addr)
+      },
+      PRG_ROM_MAP ..= PRG_ROM_MAP_END => self.read_prg_rom(addr),
+      _ => 0,
+    }
+  }
+
   fn mem_write(&mut self, addr: u16, data: u8) {
     match addr {
       RAM ..= RAM_MIRRORS_END => {
@@ -71,17 +137,9 @@ impl<'a> Mem for Bus<'a> {
 
       0x2002 => panic!("Attempted to write to PPU status register"),
 
-      0x4014 => {
-        let mut buf: [u8; 256] = [0; 256];
-        for i in 0..=0xFF {
-          let hi = (data as u16) << 8;
-          buf[i] = self.mem_read(hi + i as u16);
-        }
+      0x4014 => self.oam_dma(data),
 
-        self.ppu.write_oam_dma(&buf);
-      }
-
-      0x4016 => self.joypad.write(data),
+      0x4016 => self.controllers.write(data),
 
       0x2008 ..= PPU_REGISTERS_MIRRORS_END => {
         let mirror_down_addr = addr & PPU_MIRROR_MASK;
@@ -99,22 +157,90 @@ impl<'a> Mem for Bus<'a> {
   }
 }
 
+impl<'a> CpuBus for Bus<'a> {
+  // Steps a CPU cycle at a time so the gameloop sees every frame of a long advance
+  fn tick(&mut self, cycles: usize) {
+    let (dots, per_cycles) = self.region.dots_per_cycle();
+    for _ in 0..cycles {
+      self.cycles += 1;
+      self.dot_remainder += dots;
+
+      // The frame is handed over as vblank starts, whether or not NMIs are enabled
+      let frame = self.ppu.frame_count();
+      self.ppu.tick(self.dot_remainder / per_cycles);
+      self.dot_remainder %= per_cycles;
+
+      if self.ppu.frame_count() != frame {
+        (self.gameloop_callback)(&self.ppu, &mut self.controllers);
+      }
+    }
+  }
+
+  fn nmi_pending(&mut self) -> bool {
+    self.ppu.nmi_interrupt_ready()
+  }
+
+  fn poll_nmi_interrupt(&mut self) -> Option<bool> {
+    self.ppu.poll_nmi_interrupt()
+  }
+
+  fn cycles(&self) -> usize {
+    self.cycles
+  }
+
+  // Everything but the cartridge and the controllers plugged in starts over
+  fn power_on(&mut self) {
+    self.ram_pattern.fill(&mut self.cpu_vram);
+    self.ppu.power_on(self.ram_pattern);
+    self.controllers.write(0);
+    self.dot_remainder = 0;
+    self.cycles = 0;
+  }
+
+  // The reset line only reaches the CPU and the PPU, RAM keeps its contents
+  fn reset(&mut self) {
+    self.ppu.reset();
+    self.controllers.write(0);
+  }
+}
+
 impl<'a> Bus<'a> {
   pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
   where
-    F: FnMut(&PPU, &mut Joypad) + 'call,
+    F: FnMut(&PPU, &mut Controllers) + 'call,
   {
+    let region = rom.region.unwrap_or_default();
+    let mut ppu = PPU::new(rom.chr_rom, rom.screen_mirroring);
+    ppu.set_region(region);
+
     Bus {
       cpu_vram: [0; 2048],
       prg_rom: rom.prg_rom,
       // program_counter: [0x0, 0x86],
-      ppu: PPU::new(rom.chr_rom, rom.screen_mirroring),
-      joypad: Joypad::new(),
+      ppu,
+      controllers: Controllers::new(),
+      ram_pattern: RamPattern::Zeros,
+      region,
+      dot_remainder: 0,
       cycles: 0,
       gameloop_callback: Box::from(gameloop_callback),
     }
   }
 
+  // Defaults to the region in the ROM header, meant to be set before power on
+  pub fn set_region(&mut self, region: Region) {
+    self.region = region;
+    self.ppu.set_region(region);
+  }
+
+  pub fn region(&self) -> Region {
+    self.region
+  }
+
+  pub fn set_ram_pattern(&mut self, pattern: RamPattern) {
+    self.ram_pattern = pattern;
+  }
+
   fn read_prg_rom(&self, mut addr: u16) -> u8 {
     addr -= 0x8000;
 
@@ -126,19 +252,169 @@ impl<'a> Bus<'a> {
     self.prg_rom[addr as usize]
   }
 
-  pub fn tick(&mut self, cycles: u8) {
-    self.cycles += cycles as usize;
+  // The CPU is halted while the page is copied to OAM one read and one write per cycle. Halting
+  // takes a cycle, plus another when the write landed on an odd cycle so the copy starts on a
+  // read cycle, 513 or 514 cycles in all
+  fn oam_dma(&mut self, page: u8) {
+    let odd = self.cycles % 2 == 1;
+    self.tick(1);
+    if odd {
+      self.tick(1);
+    }
+
+    let hi = (page as u16) << 8;
+    for i in 0..=0xFF {
+      self.tick(1);
+      let data = self.mem_read(hi + i);
+      self.tick(1);
+      self.ppu.write_to_oam_data(data);
+    }
+  }
+
+  pub fn ppu(&self) -> &PPU {
+    &self.ppu
+  }
 
-    let nmi_before = self.ppu.nmi_interrupt_ready();
-    self.ppu.tick(cycles * 3);
-    let nmi_after = self.ppu.nmi_interrupt_ready();
+  pub fn controllers_mut(&mut self) -> &mut Controllers {
+    &mut self.controllers
+  }
+}
 
-    if !nmi_before && nmi_after {
-      (self.gameloop_callback)(&self.ppu, &mut self.joypad);
+impl<'a> Snapshot for Bus<'a> {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_u64(savestate::hash(&self.prg_rom));
+    w.write_bytes(&self.cpu_vram);
+    w.write_u8(self.region as u8);
+    w.write_u8(self.dot_remainder as u8);
+    w.write_u64(self.cycles as u64);
+    self.ppu.save(w);
+    self.controllers.save(w);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    if r.read_u64()? != savestate::hash(&self.prg_rom) {
+      return Err("Save state belongs to a different ROM".to_string());
+    }
+    r.read_bytes(&mut self.cpu_vram)?;
+    if r.read_u8()? != self.region as u8 {
+      return Err("Save state uses a different region".to_string());
     }
+    self.dot_remainder = r.read_u8()? as usize;
+    self.cycles = r.read_u64()? as usize;
+    self.ppu.load(r)?;
+    self.controllers.load(r)
   }
+}
 
-  pub fn poll_nmi_interrupt(&mut self) -> Option<bool> {
-    self.ppu.poll_nmi_interrupt()
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::controller::DeviceKind;
+  use crate::controller::InputState;
+  use crate::joypad::JoypadButton;
+  use crate::rom::test::test_rom;
+
+  #[test]
+  fn test_second_controller() {
+    let mut bus = Bus::new(test_rom(), |_: &PPU, _: &mut Controllers| {});
+    let mut input = InputState::default();
+    input.pads[0] = JoypadButton::BUTTON_A;
+    input.pads[1] = JoypadButton::BUTTON_B;
+    bus.controllers.update(&input);
+
+    bus.mem_write(0x4016, 1);
+    bus.mem_write(0x4016, 0);
+
+    assert_eq!(bus.mem_read(0x4016), 0x41);
+    assert_eq!(bus.mem_read(0x4017), 0x40);
+    assert_eq!(bus.mem_peek(0x4016), 0x40);
+    assert_eq!(bus.mem_read(0x4016), 0x40);
+    assert_eq!(bus.mem_read(0x4017), 0x41);
+  }
+
+  #[test]
+  fn test_zapper_follows_beam() {
+    let mut bus = Bus::new(test_rom(), |_: &PPU, _: &mut Controllers| {});
+    bus.controllers.plug(1, DeviceKind::Zapper.create());
+    let input = InputState {
+      mouse_x: 100,
+      mouse_y: 50,
+      ..InputState::default()
+    };
+    bus.controllers.update(&input);
+
+    // Every palette entry white, so the whole picture is bright
+    bus.mem_write(0x2006, 0x3f);
+    bus.mem_write(0x2006, 0x00);
+    for _ in 0..0x20 {
+      bus.mem_write(0x2007, 0x30);
+    }
+
+    let light_at = |bus: &mut Bus, scanline: u16| {
+      while bus.ppu.scanline() != scanline {
+        bus.tick(1);
+      }
+      bus.mem_read(0x4017) & 0b0000_1000 == 0
+    };
+    assert!(!light_at(&mut bus, 40));
+    assert!(light_at(&mut bus, 55));
+    assert!(!light_at(&mut bus, 100));
+  }
+
+  #[test]
+  fn test_power_on_and_reset() {
+    let mut bus = Bus::new(test_rom(), |_: &PPU, _: &mut Controllers| {});
+    bus.set_ram_pattern(RamPattern::Ones);
+    bus.power_on();
+    assert_eq!(bus.mem_read(0x0000), 0xff);
+    assert_eq!(bus.mem_read(0x07ff), 0xff);
+
+    bus.mem_write(0x0010, 0x42);
+    bus.mem_write(0x2000, 0x80);
+    bus.reset();
+    assert_eq!(bus.mem_read(0x0010), 0x42);
+    assert!(!bus.ppu.control.should_generate_vblank_nmi());
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_oam_dma() {
+    let mut bus = Bus::new(test_rom(), |_: &PPU, _: &mut Controllers| {});
+    for i in 0..=0xFF {
+      bus.mem_write(0x0200 + i, i as u8);
+    }
+    bus.mem_write(0x2003, 0x10);
+
+    bus.tick(2);
+    bus.mem_write(0x4014, 0x02);
+    assert_eq!(bus.cycles(), 2 + 513);
+    assert_eq!(bus.ppu.oam_data[0x10], 0x00);
+    assert_eq!(bus.ppu.oam_data[0x0f], 0xff);
+
+    bus.mem_write(0x4014, 0x02);
+    assert_eq!(bus.cycles(), 2 + 513 + 514);
+  }
+
+  #[test]
+  fn test_long_tick() {
+    let mut frames = vec![];
+    let mut bus = Bus::new(test_rom(), |ppu: &PPU, _: &mut Controllers| frames.push(ppu.scanline()));
+    bus.tick(100_000);
+    assert_eq!(bus.cycles(), 100_000);
+    assert_eq!(bus.ppu().frame_count(), 3);
+    drop(bus);
+    assert_eq!(frames, vec![241, 241, 241]);
+  }
+
+  #[test]
+  fn test_pal_clock_ratio() {
+    let mut bus = Bus::new(test_rom(), |_: &PPU, _: &mut Controllers| {});
+    bus.set_region(Region::Pal);
+    bus.power_on();
+    bus.tick(5);
+    assert_eq!((bus.ppu().scanline(), bus.ppu().dot()), (0, 16));
+    bus.tick(2);
+    assert_eq!(bus.ppu().dot(), 22);
+    bus.tick(3);
+    assert_eq!(bus.ppu().dot(), 32);
+  }
+}
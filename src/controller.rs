@@ -0,0 +1,670 @@
+use crate::joypad::Joypad;
+use crate::joypad::JoypadButton;
+use crate::ppu::PPU;
+use crate::render;
+use crate::render::frame::Frame;
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+// Pixels around the cursor the zapper sees, and how bright one has to be to register
+const ZAPPER_RADIUS: i32 = 2;
+const ZAPPER_THRESHOLD: u16 = 0x1e0;
+// Scanlines a bright pixel keeps the photodiode lit after the beam drew it
+const ZAPPER_LIT_SCANLINES: i32 = 20;
+
+// Range of the Arkanoid paddle potentiometer reading
+const PADDLE_MIN: u8 = 0x62;
+const PADDLE_MAX: u8 = 0xf2;
+
+// Power Pad buttons (numbered 1-12) in the order they are shifted out on D3 and D4
+const POWER_PAD_D3_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const POWER_PAD_D4_ORDER: [u8; 4] = [4, 3, 12, 8];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+  None,
+  Joypad,
+  Zapper,
+  FourScore,
+  Paddle,
+  PowerPad,
+}
+
+impl DeviceKind {
+  pub fn from_name(name: &str) -> Result<DeviceKind, String> {
+    match name {
+      "none" => Ok(DeviceKind::None),
+      "pad" => Ok(DeviceKind::Joypad),
+      "zapper" => Ok(DeviceKind::Zapper),
+      "fourscore" => Ok(DeviceKind::FourScore),
+      "paddle" => Ok(DeviceKind::Paddle),
+      "powerpad" => Ok(DeviceKind::PowerPad),
+      _ => Err(format!("Unknown controller: {}", name)),
+    }
+  }
+
+  pub fn create(self) -> Box<dyn ControllerPortDevice> {
+    match self {
+      DeviceKind::None => Box::new(Unplugged),
+      DeviceKind::Joypad => Box::new(Joypad::new()),
+      DeviceKind::Zapper => Box::new(Zapper::new()),
+      DeviceKind::FourScore => Box::new(FourScore::new()),
+      DeviceKind::Paddle => Box::new(Paddle::new()),
+      DeviceKind::PowerPad => Box::new(PowerPad::new()),
+    }
+  }
+}
+
+// Everything the frontend collected for a frame, each device picks the parts it uses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputState {
+  // Players 1 to 4, players 3 and 4 are only reachable through a Four Score
+  pub pads: [JoypadButton; 4],
+  // Mouse position in frame pixels, drives the zapper and the paddle
+  pub mouse_x: i32,
+  pub mouse_y: i32,
+  pub trigger: bool,
+  // Power Pad buttons, bit 0 is button 1
+  pub power_pad: u16,
+}
+
+pub trait ControllerPortDevice: Snapshot {
+  fn kind(&self) -> DeviceKind;
+
+  // Strobe written to $4016, shared by both ports
+  fn write(&mut self, data: u8);
+
+  // Data lines D0-D4 of the port
+  fn read(&mut self) -> u8;
+
+  fn peek(&self) -> u8;
+
+  // Called once per frame with the new input, port is 0 for $4016 and 1 for $4017
+  fn update(&mut self, port: usize, input: &InputState);
+
+  // Called before a read with the picture being drawn, None until the frame starts drawing, and
+  // the scanline and dot the beam is on
+  fn sense_light(&mut self, _frame: Option<&Frame>, _scanline: u16, _dot: usize) {}
+}
+
+pub struct Controllers {
+  ports: [Box<dyn ControllerPortDevice>; 2],
+  // Picture of the frame the PPU is drawing and its frame count, rendered for the zapper
+  light_frame: Option<(usize, Frame)>,
+}
+
+impl Controllers {
+  pub fn new() -> Self {
+    Controllers {
+      ports: [DeviceKind::Joypad.create(), DeviceKind::Joypad.create()],
+      light_frame: None,
+    }
+  }
+
+  pub fn plug(&mut self, port: usize, device: Box<dyn ControllerPortDevice>) {
+    self.ports[port] = device;
+  }
+
+  pub fn kind(&self, port: usize) -> DeviceKind {
+    self.ports[port].kind()
+  }
+
+  pub fn write(&mut self, data: u8) {
+    for device in self.ports.iter_mut() {
+      device.write(data);
+    }
+  }
+
+  pub fn read(&mut self, port: usize) -> u8 {
+    self.ports[port].read()
+  }
+
+  pub fn peek(&self, port: usize) -> u8 {
+    self.ports[port].peek()
+  }
+
+  pub fn update(&mut self, input: &InputState) {
+    for (port, device) in self.ports.iter_mut().enumerate() {
+      device.update(port, input);
+    }
+  }
+
+  // Games set up the picture the zapper looks for during vblank, so it's rendered at the first
+  // read once the beam is back in the visible part of the frame
+  pub fn sense_light(&mut self, port: usize, ppu: &PPU) {
+    if self.ports[port].kind() != DeviceKind::Zapper {
+      return;
+    }
+
+    let scanline = ppu.scanline();
+    let frame_count = ppu.frame_count();
+    let stale = !matches!(&self.light_frame, Some((count, _)) if *count == frame_count);
+    if stale && scanline < Frame::HEIGHT as u16 {
+      let mut frame = self.light_frame.take().map_or_else(Frame::new, |(_, frame)| frame);
+      render::render(ppu, &mut frame);
+      self.light_frame = Some((frame_count, frame));
+    }
+
+    let frame = match &self.light_frame {
+      Some((count, frame)) if *count == frame_count => Some(frame),
+      _ => None,
+    };
+    self.ports[port].sense_light(frame, scanline, ppu.dot());
+  }
+}
+
+impl Default for Controllers {
+  fn default() -> Self {
+    Controllers::new()
+  }
+}
+
+impl Snapshot for Controllers {
+  fn save(&self, w: &mut StateWriter) {
+    for device in self.ports.iter() {
+      w.write_u8(device.kind() as u8);
+      device.save(w);
+    }
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    for device in self.ports.iter_mut() {
+      if r.read_u8()? != device.kind() as u8 {
+        return Err("Save state uses different controllers".to_string());
+      }
+      device.load(r)?;
+    }
+    Ok(())
+  }
+}
+
+pub struct Unplugged;
+
+impl ControllerPortDevice for Unplugged {
+  fn kind(&self) -> DeviceKind {
+    DeviceKind::None
+  }
+
+  fn write(&mut self, _data: u8) {}
+
+  fn read(&mut self) -> u8 {
+    0
+  }
+
+  fn peek(&self) -> u8 {
+    0
+  }
+
+  fn update(&mut self, _port: usize, _input: &InputState) {}
+}
+
+impl Snapshot for Unplugged {
+  fn save(&self, _w: &mut StateWriter) {}
+
+  fn load(&mut self, _r: &mut StateReader) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+impl ControllerPortDevice for Joypad {
+  fn kind(&self) -> DeviceKind {
+    DeviceKind::Joypad
+  }
+
+  fn write(&mut self, data: u8) {
+    Joypad::write(self, data)
+  }
+
+  fn read(&mut self) -> u8 {
+    Joypad::read(self)
+  }
+
+  fn peek(&self) -> u8 {
+    Joypad::peek(self)
+  }
+
+  fn update(&mut self, port: usize, input: &InputState) {
+    self.set_buttons(input.pads[port]);
+  }
+}
+
+// Light sense on D3 (low while light is seen) and trigger on D4, no strobe involved
+pub struct Zapper {
+  light: bool,
+  trigger: bool,
+  // Where the zapper points, in frame pixels
+  x: i32,
+  y: i32,
+}
+
+impl Zapper {
+  pub fn new() -> Self {
+    Zapper {
+      light: false,
+      trigger: false,
+      x: 0,
+      y: 0,
+    }
+  }
+
+  // Bright pixels around the cursor count once the beam drew them, until they fade
+  fn sees_light(&self, frame: &Frame, scanline: u16, dot: usize) -> bool {
+    let beam_y = scanline as i32;
+    // Dot 1 draws pixel 0
+    let beam_x = dot as i32;
+    for y in self.y - ZAPPER_RADIUS..=self.y + ZAPPER_RADIUS {
+      if y < 0 || y >= Frame::HEIGHT as i32 || y > beam_y || beam_y - y >= ZAPPER_LIT_SCANLINES {
+        continue;
+      }
+      for x in self.x - ZAPPER_RADIUS..=self.x + ZAPPER_RADIUS {
+        if x < 0 || x >= Frame::WIDTH as i32 || (y == beam_y && x >= beam_x) {
+          continue;
+        }
+        let base = (y as usize * Frame::WIDTH + x as usize) * 3;
+        let brightness = frame.data[base..base + 3].iter().map(|c| *c as u16).sum::<u16>();
+        if brightness >= ZAPPER_THRESHOLD {
+          return true;
+        }
+      }
+    }
+    false
+  }
+}
+
+impl Default for Zapper {
+  fn default() -> Self {
+    Zapper::new()
+  }
+}
+
+impl ControllerPortDevice for Zapper {
+  fn kind(&self) -> DeviceKind {
+    DeviceKind::Zapper
+  }
+
+  fn write(&mut self, _data: u8) {}
+
+  fn read(&mut self) -> u8 {
+    self.peek()
+  }
+
+  fn peek(&self) -> u8 {
+    let light = if self.light { 0 } else { 0b0000_1000 };
+    let trigger = if self.trigger { 0b0001_0000 } else { 0 };
+    light | trigger
+  }
+
+  fn update(&mut self, _port: usize, input: &InputState) {
+    self.trigger = input.trigger;
+    self.x = input.mouse_x;
+    self.y = input.mouse_y;
+  }
+
+  fn sense_light(&mut self, frame: Option<&Frame>, scanline: u16, dot: usize) {
+    self.light = frame.is_some_and(|frame| self.sees_light(frame, scanline, dot));
+  }
+}
+
+impl Snapshot for Zapper {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_bool(self.light);
+    w.write_bool(self.trigger);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    self.light = r.read_bool()?;
+    self.trigger = r.read_bool()?;
+    Ok(())
+  }
+}
+
+// Two pads on one port, read as 8 bits for each pad followed by an 8 bit port signature
+pub struct FourScore {
+  strobe: bool,
+  bit_index: u8,
+  first: JoypadButton,
+  second: JoypadButton,
+  signature: u8,
+}
+
+impl FourScore {
+  pub fn new() -> Self {
+    FourScore {
+      strobe: false,
+      bit_index: 0,
+      first: JoypadButton::empty(),
+      second: JoypadButton::empty(),
+      signature: 0,
+    }
+  }
+}
+
+impl Default for FourScore {
+  fn default() -> Self {
+    FourScore::new()
+  }
+}
+
+impl ControllerPortDevice for FourScore {
+  fn kind(&self) -> DeviceKind {
+    DeviceKind::FourScore
+  }
+
+  fn write(&mut self, data: u8) {
+    self.strobe = data & 1 == 1;
+    if self.strobe {
+      self.bit_index = 0;
+    }
+  }
+
+  fn read(&mut self) -> u8 {
+    let response = self.peek();
+    if !self.strobe && self.bit_index < 24 {
+      self.bit_index += 1;
+    }
+    response
+  }
+
+  fn peek(&self) -> u8 {
+    let (byte, bit) = (self.bit_index / 8, self.bit_index % 8);
+    match byte {
+      0 => (self.first.bits() >> bit) & 1,
+      1 => (self.second.bits() >> bit) & 1,
+      2 => (self.signature >> bit) & 1,
+      _ => 1,
+    }
+  }
+
+  fn update(&mut self, port: usize, input: &InputState) {
+    // Port 1 carries players 1 and 3, port 2 players 2 and 4
+    self.first = input.pads[port];
+    self.second = input.pads[port + 2];
+    // Shifted out LSB first, reads 17-24 give 0,0,0,1,0,0,0,0 on $4016 and 0,0,1,0,0,0,0,0 on $4017
+    self.signature = if port == 0 { 0x08 } else { 0x04 };
+  }
+}
+
+impl Snapshot for FourScore {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_bool(self.strobe);
+    w.write_u8(self.bit_index);
+    w.write_u8(self.first.bits());
+    w.write_u8(self.second.bits());
+    w.write_u8(self.signature);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    self.strobe = r.read_bool()?;
+    self.bit_index = r.read_u8()?;
+    self.first = JoypadButton::from_bits_truncate(r.read_u8()?);
+    self.second = JoypadButton::from_bits_truncate(r.read_u8()?);
+    self.signature = r.read_u8()?;
+    Ok(())
+  }
+}
+
+// Arkanoid controller, the strobe latches the knob position which is then shifted out
+// inverted and MSB first on D4, with the button on D3
+pub struct Paddle {
+  strobe: bool,
+  position: u8,
+  button: bool,
+  shift: u8,
+}
+
+impl Paddle {
+  pub fn new() -> Self {
+    Paddle {
+      strobe: false,
+      position: PADDLE_MIN,
+      button: false,
+      shift: 0,
+    }
+  }
+}
+
+impl Default for Paddle {
+  fn default() -> Self {
+    Paddle::new()
+  }
+}
+
+impl ControllerPortDevice for Paddle {
+  fn kind(&self) -> DeviceKind {
+    DeviceKind::Paddle
+  }
+
+  fn write(&mut self, data: u8) {
+    self.strobe = data & 1 == 1;
+    if self.strobe {
+      self.shift = !self.position;
+    }
+  }
+
+  fn read(&mut self) -> u8 {
+    let response = self.peek();
+    if !self.strobe {
+      self.shift <<= 1;
+    }
+    response
+  }
+
+  fn peek(&self) -> u8 {
+    let button = if self.button { 0b0000_1000 } else { 0 };
+    ((self.shift >> 7) << 4) | button
+  }
+
+  fn update(&mut self, _port: usize, input: &InputState) {
+    let x = input.mouse_x.clamp(0, Frame::WIDTH as i32 - 1);
+    let range = (PADDLE_MAX - PADDLE_MIN) as i32;
+    self.position = PADDLE_MIN + (x * range / (Frame::WIDTH as i32 - 1)) as u8;
+    self.button = input.trigger;
+  }
+}
+
+impl Snapshot for Paddle {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_bool(self.strobe);
+    w.write_u8(self.position);
+    w.write_bool(self.button);
+    w.write_u8(self.shift);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    self.strobe = r.read_bool()?;
+    self.position = r.read_u8()?;
+    self.button = r.read_bool()?;
+    self.shift = r.read_u8()?;
+    Ok(())
+  }
+}
+
+// 12 button floor mat, both serial streams are latched by the strobe and read out together
+pub struct PowerPad {
+  strobe: bool,
+  buttons: u16,
+  d3: u8,
+  d4: u8,
+}
+
+impl PowerPad {
+  pub fn new() -> Self {
+    PowerPad {
+      strobe: false,
+      buttons: 0,
+      d3: 0,
+      d4: 0,
+    }
+  }
+
+  fn latch(&mut self) {
+    let pressed = |button: &u8| (self.buttons >> (button - 1)) & 1 == 1;
+    // Unused bits read back as 1 once the buttons have been shifted out
+    self.d3 = POWER_PAD_D3_ORDER.iter().rev().fold(0, |bits, button| (bits << 1) | pressed(button) as u8);
+    self.d4 = POWER_PAD_D4_ORDER.iter().rev().fold(0x0f, |bits, button| (bits << 1) | pressed(button) as u8);
+  }
+}
+
+impl Default for PowerPad {
+  fn default() -> Self {
+    PowerPad::new()
+  }
+}
+
+impl ControllerPortDevice for PowerPad {
+  fn kind(&self) -> DeviceKind {
+    DeviceKind::PowerPad
+  }
+
+  fn write(&mut self, data: u8) {
+    self.strobe = data & 1 == 1;
+    if self.strobe {
+      self.latch();
+    }
+  }
+
+  fn read(&mut self) -> u8 {
+    let response = self.peek();
+    if !self.strobe {
+      self.d3 = (self.d3 >> 1) | 0x80;
+      self.d4 = (self.d4 >> 1) | 0x80;
+    }
+    response
+  }
+
+  fn peek(&self) -> u8 {
+    ((self.d3 & 1) << 3) | ((self.d4 & 1) << 4)
+  }
+
+  fn update(&mut self, _port: usize, input: &InputState) {
+    self.buttons = input.power_pad;
+  }
+}
+
+impl Snapshot for PowerPad {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_bool(self.strobe);
+    w.write_u16(self.buttons);
+    w.write_u8(self.d3);
+    w.write_u8(self.d4);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    self.strobe = r.read_bool()?;
+    self.buttons = r.read_u16()?;
+    self.d3 = r.read_u8()?;
+    self.d4 = r.read_u8()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn read_bits(device: &mut dyn ControllerPortDevice, count: usize) -> Vec<u8> {
+    device.write(1);
+    device.write(0);
+    (0..count).map(|_| device.read()).collect()
+  }
+
+  #[test]
+  fn test_four_score() {
+    let mut input = InputState::default();
+    input.pads[1] = JoypadButton::BUTTON_A;
+    input.pads[3] = JoypadButton::START;
+
+    let mut four_score = FourScore::new();
+    four_score.update(1, &input);
+    let bits = read_bits(&mut four_score, 25);
+
+    assert_eq!(bits[0], 1);
+    assert_eq!(bits[1..8], [0; 7]);
+    assert_eq!(bits[8..16], [0, 0, 0, 1, 0, 0, 0, 0]);
+    assert_eq!(bits[16..24], [0, 0, 1, 0, 0, 0, 0, 0]);
+    assert_eq!(bits[24], 1);
+
+    four_score.update(0, &input);
+    let bits = read_bits(&mut four_score, 24);
+    assert_eq!(bits[16..24], [0, 0, 0, 1, 0, 0, 0, 0]);
+  }
+
+  #[test]
+  fn test_zapper() {
+    let mut frame = Frame::new();
+    frame.set_pixel(101, 50, (0xff, 0xff, 0xff));
+
+    let mut input = InputState {
+      mouse_x: 100,
+      mouse_y: 50,
+      trigger: true,
+      ..InputState::default()
+    };
+    let mut zapper = Zapper::new();
+    zapper.update(1, &input);
+
+    // Lit from the dot after the beam draws the pixel until it fades
+    let lit = [(50, 101, false), (50, 102, true), (69, 0, true), (70, 0, false), (49, 340, false)];
+    for (scanline, dot, light) in lit {
+      zapper.sense_light(Some(&frame), scanline, dot);
+      assert_eq!(zapper.read() & 0b0000_1000 == 0, light, "scanline {} dot {}", scanline, dot);
+    }
+    zapper.sense_light(Some(&frame), 52, 0);
+    assert_eq!(zapper.read(), 0b0001_0000);
+    zapper.sense_light(None, 52, 0);
+    assert_eq!(zapper.read(), 0b0001_1000);
+
+    input.mouse_x = 200;
+    input.trigger = false;
+    zapper.update(1, &input);
+    zapper.sense_light(Some(&frame), 52, 0);
+    assert_eq!(zapper.read(), 0b0000_1000);
+  }
+
+  #[test]
+  fn test_paddle() {
+    let input = InputState {
+      mouse_x: 0,
+      trigger: true,
+      ..InputState::default()
+    };
+    let mut paddle = Paddle::new();
+    paddle.update(1, &input);
+
+    // !0x62 = 0b1001_1101
+    let bits: Vec<u8> = read_bits(&mut paddle, 8).iter().map(|b| b >> 4).collect();
+    assert_eq!(bits, vec![1, 0, 0, 1, 1, 1, 0, 1]);
+    assert_eq!(paddle.peek() & 0b0000_1000, 0b0000_1000);
+  }
+
+  #[test]
+  fn test_power_pad() {
+    let input = InputState {
+      // Buttons 1 and 12
+      power_pad: 0b1000_0000_0001,
+      ..InputState::default()
+    };
+    let mut power_pad = PowerPad::new();
+    power_pad.update(1, &input);
+
+    let bits = read_bits(&mut power_pad, 9);
+    let d3: Vec<u8> = bits.iter().map(|b| (b >> 3) & 1).collect();
+    let d4: Vec<u8> = bits.iter().map(|b| (b >> 4) & 1).collect();
+    assert_eq!(d3, vec![0, 1, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(d4, vec![0, 0, 1, 0, 1, 1, 1, 1, 1]);
+  }
+
+  #[test]
+  fn test_snapshot_kind_mismatch() {
+    let controllers = Controllers::new();
+    let mut w = StateWriter::new();
+    controllers.save(&mut w);
+    let data = w.finish();
+
+    let mut other = Controllers::new();
+    other.plug(1, DeviceKind::Zapper.create());
+    let mut r = StateReader::new(&data).unwrap();
+    assert_eq!(other.load(&mut r), Err("Save state uses different controllers".to_string()));
+  }
+}
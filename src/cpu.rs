@@ -1,17 +1,38 @@
+use crate::ops::Op;
 use crate::ops::OPS_MAP;
 use crate::bus::Bus;
 use crate::rom::Rom;
 use crate::ppu::PPU;
-use crate::joypad::Joypad;
+use crate::controller::Controllers;
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
 
-pub struct CPU<'a> {
+// Without a bus type this is the NES CPU, any other bus only needs to provide memory and a clock
+pub struct CPU<'a, B = Bus<'a>> {
   pub register_a: u8,
   pub register_x: u8,
   pub register_y: u8,
   pub status: u8,
   pub program_counter: u16,
   pub stack_pointer: u8, 
-  pub bus: Bus<'a>,
+  pub bus: B,
+  dispatch: Box<DispatchTable<'a, B>>,
+  variant: Variant,
+  // Set by the KIL opcodes, only a reset gets the CPU going again
+  jammed: bool,
+  // Whether an NMI was pending before the current cycle. The CPU polls interrupts before the last
+  // cycle of an instruction, so an NMI raised during that cycle waits for the next instruction
+  nmi_sampled: bool,
+}
+
+// The NES CPU is a 6502 with the decimal mode cut out, the plain NMOS 6502 runs ADC and SBC
+// in BCD while D is set
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Variant {
+  #[default]
+  Ricoh2A03,
+  Nmos6502,
 }
 
 #[derive(Debug)]
@@ -39,18 +60,28 @@ const F_BREAK: u8 = 0b0011_0000;
 const F_BREAK_BIT_4: u8 = 0b0001_0000;
 const F_BREAK_BIT_5: u8 = 0b0010_0000;
 
+// XAA and LXA OR the accumulator with a chip dependent value before the AND, this is the most common one
+const UNSTABLE_MAGIC: u8 = 0xee;
+
 const STACK_OFFSET: u16 = 0x100;
 const STACK_RESET: u8 = 0xfd;
 
 pub trait Mem {
   fn mem_read(&mut self, addr: u16) -> u8;
 
+  // Same as mem_read, but without side effects on registers (for tracing and debugging)
+  fn mem_peek(&self, addr: u16) -> u8;
+
   fn mem_write(&mut self, addr: u16, data: u8);
 
   fn mem_read_u16(&mut self, pos: u16) -> u16 {
     u16::from_le_bytes([self.mem_read(pos), self.mem_read(pos + 1)])
   }
 
+  fn mem_peek_u16(&self, pos: u16) -> u16 {
+    u16::from_le_bytes([self.mem_peek(pos), self.mem_peek(pos.wrapping_add(1))])
+  }
+
   fn mem_write_u16(&mut self, pos: u16, data: u16) {
     let bytes = data.to_le_bytes();
     self.mem_write(pos, bytes[0]);
@@ -58,11 +89,40 @@ pub trait Mem {
   }
 }
 
-impl<'a> Mem for CPU<'a> {
+// Everything the CPU needs from the machine besides memory. The CPU ticks the bus for every cycle
+// it spends, and the NMI line is left to the buses that have one
+pub trait CpuBus: Mem {
+  fn tick(&mut self, cycles: usize);
+
+  fn cycles(&self) -> usize;
+
+  fn nmi_pending(&mut self) -> bool {
+    false
+  }
+
+  fn poll_nmi_interrupt(&mut self) -> Option<bool> {
+    None
+  }
+
+  // Level of the IRQ line, true while a device holds it low
+  fn irq_pending(&mut self) -> bool {
+    false
+  }
+
+  fn power_on(&mut self) {}
+
+  fn reset(&mut self) {}
+}
+
+impl<'a, B: CpuBus> Mem for CPU<'a, B> {
   fn mem_read(&mut self, addr: u16) -> u8 {
     self.bus.mem_read(addr)
   }
 
+  fn mem_peek(&self, addr: u16) -> u8 {
+    self.bus.mem_peek(addr)
+  }
+
   fn mem_write(&mut self, addr: u16, data: u8) {
     self.bus.mem_write(addr, data);
   }
@@ -72,32 +132,165 @@ fn page_crossed(addr1: u16, addr2: u16) -> bool {
   addr1 & 0xFF00 != addr2 & 0xFF00
 }
 
+type OpHandler<'a, B> = fn(&mut CPU<'a, B>, &Op);
+
+type DispatchTable<'a, B> = [Option<(&'static Op, OpHandler<'a, B>)>; 256];
+
+// Indexed directly by opcode so dispatch is a single lookup. The handlers depend on the bus type,
+// so every CPU builds its own from the ops table
+fn dispatch_table<'a, B: CpuBus>() -> Box<DispatchTable<'a, B>> {
+  let mut table: Box<DispatchTable<'a, B>> = Box::new([None; 256]);
+  for op in OPS_MAP.values() {
+    table[op.code as usize] = Some((*op, op_handler(op)));
+  }
+  table
+}
+
+fn op_handler<'a, B: CpuBus>(op: &Op) -> OpHandler<'a, B> {
+  match (op.ins, op.code) {
+    ("LDA", _) => |cpu, op| cpu.lda(&op.mode),
+    ("LDX", _) => |cpu, op| cpu.ldx(&op.mode),
+    ("LDY", _) => |cpu, op| cpu.ldy(&op.mode),
+    ("STA", _) => |cpu, op| cpu.sta(&op.mode),
+    ("STX", _) => |cpu, op| cpu.stx(&op.mode),
+    ("STY", _) => |cpu, op| cpu.sty(&op.mode),
+    ("TAX", _) => |cpu, _| cpu.tax(),
+    ("TAY", _) => |cpu, _| cpu.tay(),
+    ("TSX", _) => |cpu, _| cpu.tsx(),
+    ("TXA", _) => |cpu, _| cpu.txa(),
+    ("TXS", _) => |cpu, _| cpu.txs(),
+    ("TYA", _) => |cpu, _| cpu.tya(),
+    ("INC", _) => |cpu, op| cpu.inc(&op.mode),
+    ("INX", _) => |cpu, _| cpu.inx(),
+    ("INY", _) => |cpu, _| cpu.iny(),
+    ("DEC", _) => |cpu, op| cpu.dec(&op.mode),
+    ("DEX", _) => |cpu, _| cpu.dex(),
+    ("DEY", _) => |cpu, _| cpu.dey(),
+    ("ADC", _) => |cpu, op| cpu.adc(&op.mode),
+    ("SBC", _) | ("*SBC", _) => |cpu, op| cpu.sbc(&op.mode),
+    ("AND", _) => |cpu, op| cpu.and(&op.mode),
+    ("ORA", _) => |cpu, op| cpu.or(&op.mode),
+    ("EOR", _) => |cpu, op| cpu.eor(&op.mode),
+    ("CMP", _) => |cpu, op| cpu.compare(&op.mode, cpu.register_a),
+    ("CPX", _) => |cpu, op| cpu.compare(&op.mode, cpu.register_x),
+    ("CPY", _) => |cpu, op| cpu.compare(&op.mode, cpu.register_y),
+    ("ROL", _) => |cpu, op| cpu.rol(&op.mode),
+    ("ROR", _) => |cpu, op| cpu.ror(&op.mode),
+    ("ASL", _) => |cpu, op| cpu.asl(&op.mode),
+    ("LSR", _) => |cpu, op| cpu.lsr(&op.mode),
+    ("CLC", _) => |cpu, _| cpu.clc(),
+    ("CLD", _) => |cpu, _| cpu.cld(),
+    ("CLI", _) => |cpu, _| cpu.cli(),
+    ("CLV", _) => |cpu, _| cpu.clv(),
+    ("SEC", _) => |cpu, _| cpu.sec(),
+    ("SED", _) => |cpu, _| cpu.sed(),
+    ("SEI", _) => |cpu, _| cpu.sei(),
+    ("BCC", _) => |cpu, _| cpu.branch(F_CARRY, false),
+    ("BCS", _) => |cpu, _| cpu.branch(F_CARRY, true),
+    ("BNE", _) => |cpu, _| cpu.branch(F_ZERO, false),
+    ("BEQ", _) => |cpu, _| cpu.branch(F_ZERO, true),
+    ("BPL", _) => |cpu, _| cpu.branch(F_NEG, false),
+    ("BMI", _) => |cpu, _| cpu.branch(F_NEG, true),
+    ("BVC", _) => |cpu, _| cpu.branch(F_OVRFLW, false),
+    ("BVS", _) => |cpu, _| cpu.branch(F_OVRFLW, true),
+    ("JMP", 0x4c) => |cpu, _| cpu.jmp_absolute(),
+    ("JMP", 0x6c) => |cpu, _| cpu.jmp_indirect(),
+    ("JSR", _) => |cpu, _| cpu.jsr(),
+    ("RTS", _) => |cpu, _| cpu.rts(),
+    ("BIT", _) => |cpu, op| cpu.bit(&op.mode),
+    ("RTI", _) => |cpu, _| cpu.rti(),
+    ("PHA", _) => |cpu, _| cpu.pha(),
+    ("PHP", _) => |cpu, _| cpu.php(),
+    ("PLA", _) => |cpu, _| cpu.pla(),
+    ("PLP", _) => |cpu, _| cpu.plp(),
+    ("BRK", _) => |cpu, _| cpu.brk(),
+    ("NOP", _) => |_, _| {},
+    ("*NOP", _) => |cpu, op| cpu.nop(&op.mode),
+    ("*LAX", _) => |cpu, op| cpu.lax(&op.mode),
+    ("*SAX", _) => |cpu, op| cpu.sax(&op.mode),
+    ("*DCP", _) => |cpu, op| cpu.dcp(&op.mode),
+    ("*ISB", _) => |cpu, op| cpu.isb(&op.mode),
+    ("*SLO", _) => |cpu, op| cpu.slo(&op.mode),
+    ("*RLA", _) => |cpu, op| cpu.rla(&op.mode),
+    ("*SRE", _) => |cpu, op| cpu.sre(&op.mode),
+    ("*RRA", _) => |cpu, op| cpu.rra(&op.mode),
+    ("*ANC", _) => |cpu, op| cpu.anc(&op.mode),
+    ("*ALR", _) => |cpu, op| cpu.alr(&op.mode),
+    ("*ARR", _) => |cpu, op| cpu.arr(&op.mode),
+    ("*AXS", _) => |cpu, op| cpu.axs(&op.mode),
+    ("*XAA", _) => |cpu, op| cpu.xaa(&op.mode),
+    ("*LXA", _) => |cpu, op| cpu.lxa(&op.mode),
+    ("*LAS", _) => |cpu, op| cpu.las(&op.mode),
+    ("*SHY", _) => |cpu, _| cpu.shy(),
+    ("*SHX", _) => |cpu, _| cpu.shx(),
+    ("*TAS", _) => |cpu, _| cpu.tas(),
+    ("*AHX", _) => |cpu, op| cpu.ahx(&op.mode),
+    ("*KIL", _) => |cpu, _| cpu.jammed = true,
+    _ => panic!("no handler for opcode {:02x} ({})", op.code, op.ins),
+  }
+}
+
 impl<'a> CPU<'a> {
 
   pub fn new(rom: Rom) -> Self {
-    CPU {
-      register_a: 0,
-      register_x: 0,
-      register_y: 0,
-      status: 0, // TODO: Change to 0x24 and fix tests
-      program_counter: 0,
-      stack_pointer: STACK_RESET,
-      bus: Bus::new(rom, |_: &PPU, _: &mut Joypad| {}),
-    }
+    CPU::with_bus(Bus::new(rom, |_: &PPU, _: &mut Controllers| {}))
   }
 
   pub fn new_with_gameloop<F>(rom: Rom, gameloop_callback: F) -> Self
   where
-    F: FnMut(&PPU, &mut Joypad) + 'a,
+    F: FnMut(&PPU, &mut Controllers) + 'a,
   {
+    CPU::with_bus(Bus::new(rom, gameloop_callback))
+  }
+
+  pub fn save_state(&self) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    self.save(&mut w);
+    w.finish()
+  }
+
+  // Leaves the machine untouched if the state can't be read
+  pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+    let backup = self.save_state();
+
+    let result = StateReader::new(data).and_then(|mut r| {
+      Snapshot::load(self, &mut r)?;
+      r.finish()
+    });
+
+    if result.is_err() {
+      Snapshot::load(self, &mut StateReader::new(&backup)?)?;
+    }
+    result
+  }
+
+  // Runs until the PPU enters vblank, whether or not NMIs are enabled
+  pub fn run_frame(&mut self) -> bool {
+    let frame = self.bus.ppu().frame_count();
+    while self.bus.ppu().frame_count() == frame {
+      if !self.step_instruction() {
+        return false;
+      }
+    }
+    true
+  }
+}
+
+impl<'a, B: CpuBus> CPU<'a, B> {
+
+  pub fn with_bus(bus: B) -> Self {
     CPU {
       register_a: 0,
       register_x: 0,
       register_y: 0,
-      status: 0,
+      status: 0, // TODO: Change to 0x24 and fix tests
       program_counter: 0,
       stack_pointer: STACK_RESET,
-      bus: Bus::new(rom, gameloop_callback),
+      bus,
+      dispatch: dispatch_table(),
+      variant: Variant::default(),
+      jammed: false,
+      nmi_sampled: false,
     }
   }
 
@@ -153,52 +346,158 @@ impl<'a> CPU<'a> {
     }
   }
 
-  // Side effect: Adds a cycle if a page boundary is crossed
+  // Resolves the same address as get_absolute_address using mem_peek
+  pub fn peek_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
+    match mode {
+      AddressingMode::Immediate => addr,
+
+      AddressingMode::ZeroPage => self.mem_peek(addr) as u16,
+
+      AddressingMode::Absolute => self.mem_peek_u16(addr),
+
+      AddressingMode::ZeroPage_X => self.mem_peek(addr).wrapping_add(self.register_x) as u16,
+
+      AddressingMode::ZeroPage_Y => self.mem_peek(addr).wrapping_add(self.register_y) as u16,
+
+      AddressingMode::Absolute_X => self.mem_peek_u16(addr).wrapping_add(self.register_x as u16),
+
+      AddressingMode::Absolute_Y => self.mem_peek_u16(addr).wrapping_add(self.register_y as u16),
+
+      AddressingMode::Indirect_X => {
+        let ptr = self.mem_peek(addr).wrapping_add(self.register_x);
+        let lo = self.mem_peek(ptr as u16);
+        let hi = self.mem_peek(ptr.wrapping_add(1) as u16);
+        u16::from_le_bytes([lo, hi])
+      }
+
+      AddressingMode::Indirect_Y => {
+        let base = self.mem_peek(addr);
+        let lo = self.mem_peek(base as u16);
+        let hi = self.mem_peek(base.wrapping_add(1) as u16);
+        u16::from_le_bytes([lo, hi]).wrapping_add(self.register_y as u16)
+      }
+
+      AddressingMode::NoneAddressing => {
+        panic!("mode {:?} is not supported", mode);
+      }
+    }
+  }
+
+  // One bus cycle: the rest of the machine advances, then the access happens
+  fn read_cycle(&mut self, addr: u16) -> u8 {
+    self.nmi_sampled = self.bus.nmi_pending();
+    self.bus.tick(1);
+    self.bus.mem_read(addr)
+  }
+
+  fn write_cycle(&mut self, addr: u16, data: u8) {
+    self.nmi_sampled = self.bus.nmi_pending();
+    self.bus.tick(1);
+    self.bus.mem_write(addr, data);
+  }
+
+  fn read_cycle_u16(&mut self, pos: u16) -> u16 {
+    u16::from_le_bytes([self.read_cycle(pos), self.read_cycle(pos.wrapping_add(1))])
+  }
+
+  // Resolves the operand address with every bus access the addressing mode makes
   fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
+    self.resolve_operand_address(mode, false)
+  }
+
+  // Stores and read-modify-writes always make the dummy read before an indexed access
+  fn get_write_address(&mut self, mode: &AddressingMode) -> u16 {
+    self.resolve_operand_address(mode, true)
+  }
+
+  // Indexed modes add the index to the low byte first and read from that address, which is only
+  // the right one if no page was crossed. Reads redo the access from the fixed address when one
+  // was, so the wrong read costs a cycle only then
+  fn resolve_operand_address(&mut self, mode: &AddressingMode, always_fix: bool) -> u16 {
     match mode {
       AddressingMode::Immediate => self.program_counter,
-      AddressingMode::Absolute_X | AddressingMode::Absolute_Y => {
-        let base = self.mem_read_u16(self.program_counter);
-        let addr = self.get_absolute_address(mode, self.program_counter);
 
-        if page_crossed(base, addr) {
-          self.bus.tick(1);
-        }
+      AddressingMode::ZeroPage => self.read_cycle(self.program_counter) as u16,
 
-        addr
-      },
-      AddressingMode::Indirect_Y => {
-        let base = self.mem_read(self.program_counter);
+      AddressingMode::Absolute => self.read_cycle_u16(self.program_counter),
 
-        let lo = self.mem_read(base as u16);
-        let hi = self.mem_read(base.wrapping_add(1) as u16);
-        let deref_base = u16::from_le_bytes([lo, hi]);
+      AddressingMode::ZeroPage_X | AddressingMode::ZeroPage_Y => {
+        let base = self.read_cycle(self.program_counter);
+        self.read_cycle(base as u16);
+        let index = match mode {
+          AddressingMode::ZeroPage_X => self.register_x,
+          _ => self.register_y,
+        };
+        base.wrapping_add(index) as u16
+      }
+
+      AddressingMode::Absolute_X | AddressingMode::Absolute_Y => {
+        let base = self.read_cycle_u16(self.program_counter);
+        let index = match mode {
+          AddressingMode::Absolute_X => self.register_x,
+          _ => self.register_y,
+        };
+        self.index_address(base, index, always_fix)
+      }
 
-        let addr = self.get_absolute_address(mode, self.program_counter);
+      AddressingMode::Indirect_X => {
+        let ptr = self.read_cycle(self.program_counter);
+        self.read_cycle(ptr as u16);
 
-        if page_crossed(deref_base, addr) {
-          self.bus.tick(1);
-        }
+        let ptr = ptr.wrapping_add(self.register_x);
+        let lo = self.read_cycle(ptr as u16);
+        let hi = self.read_cycle(ptr.wrapping_add(1) as u16);
+        u16::from_le_bytes([lo, hi])
+      }
+
+      AddressingMode::Indirect_Y => {
+        let base = self.read_indirect_y_base();
+        self.index_address(base, self.register_y, always_fix)
+      }
 
-        addr
+      AddressingMode::NoneAddressing => {
+        panic!("mode {:?} is not supported", mode);
       }
-      _ => self.get_absolute_address(mode, self.program_counter),
     }
   }
 
+  fn read_indirect_y_base(&mut self) -> u16 {
+    let ptr = self.read_cycle(self.program_counter);
+    let lo = self.read_cycle(ptr as u16);
+    let hi = self.read_cycle(ptr.wrapping_add(1) as u16);
+    u16::from_le_bytes([lo, hi])
+  }
+
+  fn index_address(&mut self, base: u16, index: u8, always_fix: bool) -> u16 {
+    let addr = base.wrapping_add(index as u16);
+    if always_fix || page_crossed(base, addr) {
+      self.read_cycle((base & 0xFF00) | (addr & 0x00FF));
+    }
+    addr
+  }
+
+  // Reads the operand of a read-modify-write instruction, which writes the old value back
+  // while it works out the new one
+  fn read_for_modify(&mut self, mode: &AddressingMode) -> (u16, u8) {
+    let addr = self.get_write_address(mode);
+    let value = self.read_cycle(addr);
+    self.write_cycle(addr, value);
+    (addr, value)
+  }
+
   fn set_register_a(&mut self, value: u8) {
     self.register_a = value;
     self.update_zero_and_negative_flags(self.register_a);
   }
 
   fn stack_push(&mut self, data: u8) {
-    self.mem_write(STACK_OFFSET + (self.stack_pointer as u16), data);
+    self.write_cycle(STACK_OFFSET + (self.stack_pointer as u16), data);
     self.stack_pointer = self.stack_pointer.wrapping_sub(1);
   }
 
   fn stack_pop(&mut self) -> u8 {
     self.stack_pointer = self.stack_pointer.wrapping_add(1);
-    self.mem_read(STACK_OFFSET + (self.stack_pointer as u16))
+    self.read_cycle(STACK_OFFSET + (self.stack_pointer as u16))
   }
 
   fn stack_push_u16(&mut self, data: u16) {
@@ -207,6 +506,11 @@ impl<'a> CPU<'a> {
     self.stack_push(bytes[0]);
   }
 
+  // The cycle spent on incrementing the stack pointer before a pull reads the current slot
+  fn stack_dummy_read(&mut self) {
+    self.read_cycle(STACK_OFFSET + (self.stack_pointer as u16));
+  }
+
   fn stack_pop_u16(&mut self) -> u16 {
     let lo = self.stack_pop();
     let hi = self.stack_pop();
@@ -216,24 +520,36 @@ impl<'a> CPU<'a> {
 
   // Load functions
 
-  pub fn reset(&mut self) {
+  // Powers the whole machine up from scratch, then runs the reset sequence
+  pub fn power_on(&mut self) {
     self.register_a = 0;
     self.register_x = 0;
     self.register_y = 0;
-    self.stack_pointer = STACK_RESET;
+    self.status = F_BREAK_BIT_5;
+    // The reset sequence pulls this down to STACK_RESET
+    self.stack_pointer = STACK_RESET.wrapping_add(3);
+    self.bus.power_on();
+    self.reset();
+  }
 
-    // self.status = (self.status & !F_INT) & !F_BREAK;
-    // TODO: Remove magic number
-    self.status = 0x24;
+  // Soft reset: registers and RAM are kept, the stack pointer moves as if three bytes were
+  // pushed (without writing them) and interrupts are disabled
+  pub fn reset(&mut self) {
+    self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+    self.status |= F_INT;
+    self.jammed = false;
+    self.bus.reset();
 
     // TODO: Uncomment and fix
     self.program_counter = self.mem_read_u16(0xFFFC);
     // self.program_counter = 0x0600;
+
+    self.bus.tick(7); // Reset sequence cycles
   }
 
   pub fn load_and_run(&mut self, program: Vec<u8>) {
+    self.power_on();
     self.load(program);
-    self.reset();
     self.run()
   }
 
@@ -277,6 +593,14 @@ impl<'a> CPU<'a> {
   }
 
   fn add_to_register_a(&mut self, value: u8) {
+    if self.decimal_mode() {
+      self.add_decimal(value);
+    } else {
+      self.add_binary(value);
+    }
+  }
+
+  fn add_binary(&mut self, value: u8) {
     let carry_in = (self.status & F_CARRY != 0) as u8; 
     let (sum, overflow) = self.register_a.overflowing_add(value);
     let (result, overflow_with_carry) = sum.overflowing_add(carry_in);
@@ -289,112 +613,175 @@ impl<'a> CPU<'a> {
     self.set_register_a(result);
   }
 
+  fn decimal_mode(&self) -> bool {
+    self.variant == Variant::Nmos6502 && self.status & F_DEC != 0
+  }
+
+  // NMOS BCD addition. Z comes from the binary sum, N and V from the sum before the high digit
+  // is adjusted, so they are only meaningful for valid BCD operands
+  fn add_decimal(&mut self, value: u8) {
+    let a = self.register_a as i16;
+    let m = value as i16;
+    let carry_in = (self.status & F_CARRY != 0) as i16;
+
+    let mut low = (a & 0x0f) + (m & 0x0f) + carry_in;
+    if low >= 0x0a {
+      low = ((low + 0x06) & 0x0f) + 0x10;
+    }
+    let signed = (a & 0xf0) as u8 as i8 as i16 + (m & 0xf0) as u8 as i8 as i16 + low;
+    let mut sum = (a & 0xf0) + (m & 0xf0) + low;
+    if sum >= 0xa0 {
+      sum += 0x60;
+    }
+
+    self.update_flag(F_ZERO, (a + m + carry_in) & 0xff == 0);
+    self.update_flag(F_NEG, signed & 0x80 != 0);
+    self.update_flag(F_OVRFLW, !(-128..=127).contains(&signed));
+    self.update_flag(F_CARRY, sum >= 0x100);
+    self.register_a = sum as u8;
+  }
+
+  // NMOS BCD subtraction, all the flags are the same as in binary mode
+  fn subtract_decimal(&mut self, value: u8) {
+    let a = self.register_a as i16;
+    let m = value as i16;
+    let borrow = (self.status & F_CARRY == 0) as i16;
+
+    let mut low = (a & 0x0f) - (m & 0x0f) - borrow;
+    if low < 0 {
+      low = ((low - 0x06) & 0x0f) - 0x10;
+    }
+    let mut difference = (a & 0xf0) - (m & 0xf0) + low;
+    if difference < 0 {
+      difference -= 0x60;
+    }
+
+    self.add_binary(!value);
+    self.register_a = difference as u8;
+  }
+
   // Op functions
 
   // Arithmetic and logic instructions
   fn adc(&mut self, mode: &AddressingMode) {
-    /* Ignoring decimal mode */
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
     self.add_to_register_a(value);
   }
 
   fn sbc(&mut self, mode: &AddressingMode) {
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
+    self.subtract_from_register_a(value);
+  }
+
+  fn subtract_from_register_a(&mut self, value: u8) {
     // SBC = A - M - (1 - C)
     //     = A - M - 1 + C
     //     = A + (!M + 1) - 1 + C (two's complement)
     //     = A + !M + C
-    self.add_to_register_a(!value);
+    if self.decimal_mode() {
+      self.subtract_decimal(value);
+    } else {
+      self.add_binary(!value);
+    }
   }
 
   fn and(&mut self, mode: &AddressingMode) {
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
     self.set_register_a(self.register_a & value);
   }
 
   fn or(&mut self, mode: &AddressingMode) {
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
     self.set_register_a(self.register_a | value);
   }
 
   fn eor(&mut self, mode: &AddressingMode) {
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
     self.set_register_a(self.register_a ^ value);
   }
 
-  fn asl_accumulator(&mut self) {
-    let value = self.register_a;
-
-    self.set_register_a(value << 1);
+  // The shifts and rotates set the flags and return the result, the callers decide where it goes
+  fn asl_value(&mut self, value: u8) -> u8 {
+    let result = value << 1;
+    self.update_zero_and_negative_flags(result);
     self.update_flag(F_CARRY, value >> 7 == 1);
+    result
   }
 
-  fn asl_helper(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+  fn asl_memory(&mut self, mode: &AddressingMode) -> u8 {
+    let (addr, value) = self.read_for_modify(mode);
 
-    let result = value << 1;
-    self.mem_write(addr, result);
-    self.update_zero_and_negative_flags(result);
-    self.update_flag(F_CARRY, value >> 7 == 1);
+    let result = self.asl_value(value);
+    self.write_cycle(addr, result);
+    result
   }
 
   fn asl(&mut self, mode: &AddressingMode) {
     match mode {
-      AddressingMode::NoneAddressing => self.asl_accumulator(),
-      _ => self.asl_helper(mode)
+      AddressingMode::NoneAddressing => self.register_a = self.asl_value(self.register_a),
+      _ => {
+        self.asl_memory(mode);
+      }
     }
   }
 
-  fn lsr_accumulator(&mut self) {
-    let value = self.register_a;
-
-    self.set_register_a(value >> 1);
+  fn lsr_value(&mut self, value: u8) -> u8 {
+    let result = value >> 1;
+    self.update_zero_and_negative_flags(result);
     self.update_flag(F_CARRY, value & 1 == 1);
+    result
   }
 
-  fn lsr_helper(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+  fn lsr_memory(&mut self, mode: &AddressingMode) -> u8 {
+    let (addr, value) = self.read_for_modify(mode);
 
-    let result = value >> 1;
-    self.mem_write(addr, result);
-    self.update_zero_and_negative_flags(result);
-    self.update_flag(F_CARRY, value & 1 == 1);
+    let result = self.lsr_value(value);
+    self.write_cycle(addr, result);
+    result
   }
 
   fn lsr(&mut self, mode: &AddressingMode) {
     match mode {
-      AddressingMode::NoneAddressing => self.lsr_accumulator(),
-      _ => self.lsr_helper(mode)
+      AddressingMode::NoneAddressing => self.register_a = self.lsr_value(self.register_a),
+      _ => {
+        self.lsr_memory(mode);
+      }
     }
   }
 
   fn compare(&mut self, mode: &AddressingMode, reg: u8) {
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
+    self.compare_value(reg, value);
+  }
+
+  fn compare_value(&mut self, reg: u8, value: u8) {
     self.update_zero_and_negative_flags(reg.wrapping_sub(value));
     self.update_flag(F_CARRY, reg >= value);
   }
 
-  fn dec(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+  fn dec_memory(&mut self, mode: &AddressingMode) -> u8 {
+    let (addr, value) = self.read_for_modify(mode);
 
     let result = value.wrapping_sub(1);
-    self.mem_write(addr, result);
+    self.write_cycle(addr, result);
     self.update_zero_and_negative_flags(result);
+    result
+  }
+
+  fn dec(&mut self, mode: &AddressingMode) {
+    self.dec_memory(mode);
   }
 
   fn dex(&mut self) {
@@ -409,7 +796,7 @@ impl<'a> CPU<'a> {
 
   fn bit(&mut self, mode: &AddressingMode) {
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
     self.update_flag(F_NEG, value & 0b1000_0000 != 0);
     self.update_flag(F_OVRFLW, value & 0b0100_0000 != 0);
@@ -447,7 +834,7 @@ impl<'a> CPU<'a> {
 
   fn lda(&mut self, mode: &AddressingMode) {
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
     self.register_a = value;
     self.update_zero_and_negative_flags(self.register_a);
@@ -455,7 +842,7 @@ impl<'a> CPU<'a> {
 
   fn ldx(&mut self, mode: &AddressingMode) {
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
     self.register_x = value;
     self.update_zero_and_negative_flags(self.register_x);
@@ -463,25 +850,25 @@ impl<'a> CPU<'a> {
 
   fn ldy(&mut self, mode: &AddressingMode) {
     let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+    let value = self.read_cycle(addr);
 
     self.register_y = value;
     self.update_zero_and_negative_flags(self.register_y);
   }
 
   fn sta(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    self.mem_write(addr, self.register_a);
+    let addr = self.get_write_address(mode);
+    self.write_cycle(addr, self.register_a);
   }
 
   fn stx(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    self.mem_write(addr, self.register_x);
+    let addr = self.get_write_address(mode);
+    self.write_cycle(addr, self.register_x);
   }
 
   fn sty(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    self.mem_write(addr, self.register_y);
+    let addr = self.get_write_address(mode);
+    self.write_cycle(addr, self.register_y);
   }
 
   fn tax(&mut self) {
@@ -511,13 +898,17 @@ impl<'a> CPU<'a> {
     self.set_register_a(self.register_y);
   }
 
-  fn inc(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
+  fn inc_memory(&mut self, mode: &AddressingMode) -> u8 {
+    let (addr, value) = self.read_for_modify(mode);
 
     let result = value.wrapping_add(1);
-    self.mem_write(addr, result);
+    self.write_cycle(addr, result);
     self.update_zero_and_negative_flags(result);
+    result
+  }
+
+  fn inc(&mut self, mode: &AddressingMode) {
+    self.inc_memory(mode);
   }
 
   fn inx(&mut self) {
@@ -530,57 +921,55 @@ impl<'a> CPU<'a> {
     self.update_zero_and_negative_flags(self.register_y);
   }
 
-  fn rol_accumulator(&mut self) {
-    let carry = self.register_a >> 7;
-    let old_carry = self.status & F_CARRY != 0;
-    self.set_register_a((self.register_a << 1) | old_carry as u8);
-    self.update_flag(F_CARRY, carry == 1);
-  }
-
-  fn rol_helper(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
-
-    let carry = value >> 7;
+  fn rol_value(&mut self, value: u8) -> u8 {
     let old_carry = self.status & F_CARRY != 0;
     let result = (value << 1) | old_carry as u8;
 
-    self.mem_write(addr, result);
     self.update_zero_and_negative_flags(result);
-    self.update_flag(F_CARRY, carry == 1);
+    self.update_flag(F_CARRY, value >> 7 == 1);
+    result
+  }
+
+  fn rol_memory(&mut self, mode: &AddressingMode) -> u8 {
+    let (addr, value) = self.read_for_modify(mode);
+
+    let result = self.rol_value(value);
+    self.write_cycle(addr, result);
+    result
   }
 
   fn rol(&mut self, mode: &AddressingMode) {
     match mode {
-      AddressingMode::NoneAddressing => self.rol_accumulator(),
-      _ => self.rol_helper(mode)
+      AddressingMode::NoneAddressing => self.register_a = self.rol_value(self.register_a),
+      _ => {
+        self.rol_memory(mode);
+      }
     }
   }
 
-  fn ror_accumulator(&mut self) {
-    let carry = self.register_a & 1;
-    let old_carry = self.status & F_CARRY != 0;
-    self.set_register_a((self.register_a >> 1) | ((old_carry as u8) << 7));
-    self.update_flag(F_CARRY, carry == 1);
-  }
-
-  fn ror_helper(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    let value = self.mem_read(addr);
-
-    let carry = value & 1;
+  fn ror_value(&mut self, value: u8) -> u8 {
     let old_carry = self.status & F_CARRY != 0;
     let result = (value >> 1) | ((old_carry as u8) << 7);
 
-    self.mem_write(addr, result);
     self.update_zero_and_negative_flags(result);
-    self.update_flag(F_CARRY, carry == 1);
+    self.update_flag(F_CARRY, value & 1 == 1);
+    result
+  }
+
+  fn ror_memory(&mut self, mode: &AddressingMode) -> u8 {
+    let (addr, value) = self.read_for_modify(mode);
+
+    let result = self.ror_value(value);
+    self.write_cycle(addr, result);
+    result
   }
 
   fn ror(&mut self, mode: &AddressingMode) {
     match mode {
-      AddressingMode::NoneAddressing => self.ror_accumulator(),
-      _ => self.ror_helper(mode)
+      AddressingMode::NoneAddressing => self.register_a = self.ror_value(self.register_a),
+      _ => {
+        self.ror_memory(mode);
+      }
     }
   }
 
@@ -590,56 +979,149 @@ impl<'a> CPU<'a> {
   }
 
   fn sax(&mut self, mode: &AddressingMode) {
-    let addr = self.get_operand_address(mode);
-    self.mem_write(addr, self.register_a & self.register_x);
+    let addr = self.get_write_address(mode);
+    self.write_cycle(addr, self.register_a & self.register_x);
   }
 
+  // The combined read-modify-write instructions use the value they wrote as the operand
   fn dcp(&mut self, mode: &AddressingMode) {
-    self.dec(mode);
-    self.compare(mode, self.register_a);
+    let result = self.dec_memory(mode);
+    self.compare_value(self.register_a, result);
   }
 
   fn isb(&mut self, mode: &AddressingMode) {
-    self.inc(mode);
-    self.sbc(mode);
+    let result = self.inc_memory(mode);
+    self.subtract_from_register_a(result);
   }
 
   fn slo(&mut self, mode: &AddressingMode) {
-    self.asl(mode);
-    self.or(mode);
+    let result = self.asl_memory(mode);
+    self.set_register_a(self.register_a | result);
   }
 
   fn rla(&mut self, mode: &AddressingMode) {
-    self.rol(mode);
-    self.and(mode);
+    let result = self.rol_memory(mode);
+    self.set_register_a(self.register_a & result);
   }
 
   fn sre(&mut self, mode: &AddressingMode) {
-    self.lsr(mode);
-    self.eor(mode);
+    let result = self.lsr_memory(mode);
+    self.set_register_a(self.register_a ^ result);
   }
 
   fn rra(&mut self, mode: &AddressingMode) {
-    self.ror(mode);
-    self.adc(mode);
+    let result = self.ror_memory(mode);
+    self.add_to_register_a(result);
+  }
+
+  fn anc(&mut self, mode: &AddressingMode) {
+    self.and(mode);
+    self.update_flag(F_CARRY, self.register_a & F_NEG != 0);
+  }
+
+  fn alr(&mut self, mode: &AddressingMode) {
+    self.and(mode);
+    self.register_a = self.lsr_value(self.register_a);
+  }
+
+  // AND then ROR, with C taken from bit 6 of the result and V from bit 6 xor bit 5
+  fn arr(&mut self, mode: &AddressingMode) {
+    self.and(mode);
+    self.register_a = self.ror_value(self.register_a);
+
+    let result = self.register_a;
+    self.update_flag(F_CARRY, result & 0b0100_0000 != 0);
+    self.update_flag(F_OVRFLW, ((result >> 6) ^ (result >> 5)) & 1 != 0);
+  }
+
+  // X = (A & X) - M, a compare that keeps the result, so it ignores carry in and doesn't touch V
+  fn axs(&mut self, mode: &AddressingMode) {
+    let addr = self.get_operand_address(mode);
+    let value = self.read_cycle(addr);
+
+    let and = self.register_a & self.register_x;
+    self.register_x = and.wrapping_sub(value);
+    self.update_zero_and_negative_flags(self.register_x);
+    self.update_flag(F_CARRY, and >= value);
+  }
+
+  fn xaa(&mut self, mode: &AddressingMode) {
+    let addr = self.get_operand_address(mode);
+    let value = self.read_cycle(addr);
+
+    self.set_register_a((self.register_a | UNSTABLE_MAGIC) & self.register_x & value);
+  }
+
+  fn lxa(&mut self, mode: &AddressingMode) {
+    let addr = self.get_operand_address(mode);
+    let value = self.read_cycle(addr);
+
+    self.set_register_a((self.register_a | UNSTABLE_MAGIC) & value);
+    self.register_x = self.register_a;
+  }
+
+  fn las(&mut self, mode: &AddressingMode) {
+    let addr = self.get_operand_address(mode);
+    let value = self.read_cycle(addr) & self.stack_pointer;
+
+    self.register_x = value;
+    self.stack_pointer = value;
+    self.set_register_a(value);
+  }
+
+  // SHY, SHX, TAS and AHX store the value ANDed with the high byte of the base address plus one.
+  // When indexing crosses a page that result also replaces the high byte of the target address
+  fn store_high_and(&mut self, base: u16, index: u8, value: u8) {
+    let addr = self.index_address(base, index, true);
+    let result = value & ((base >> 8) as u8).wrapping_add(1);
+
+    let addr = if page_crossed(base, addr) {
+      (result as u16) << 8 | (addr & 0x00FF)
+    } else {
+      addr
+    };
+    self.write_cycle(addr, result);
+  }
+
+  fn shy(&mut self) {
+    let base = self.read_cycle_u16(self.program_counter);
+    self.store_high_and(base, self.register_x, self.register_y);
+  }
+
+  fn shx(&mut self) {
+    let base = self.read_cycle_u16(self.program_counter);
+    self.store_high_and(base, self.register_y, self.register_x);
+  }
+
+  fn tas(&mut self) {
+    self.stack_pointer = self.register_a & self.register_x;
+    let base = self.read_cycle_u16(self.program_counter);
+    self.store_high_and(base, self.register_y, self.stack_pointer);
+  }
+
+  fn ahx(&mut self, mode: &AddressingMode) {
+    let base = match mode {
+      AddressingMode::Indirect_Y => self.read_indirect_y_base(),
+      _ => self.read_cycle_u16(self.program_counter),
+    };
+    self.store_high_and(base, self.register_y, self.register_a & self.register_x);
   }
 
   // Branch control instructions
   fn branch(&mut self, flag: u8, branch_on_set: bool) {
+    // Casted as i8 for signed extension when casted to u16
+    let offset = self.read_cycle(self.program_counter) as i8;
     let flag_set = self.status & flag != 0;
 
     if flag_set == branch_on_set {
-      self.bus.tick(1);
-
-      // Casted as i8 for signed extension when casted to u16
-      let offset = self.mem_read(self.program_counter) as i8;
+      let next = self.program_counter.wrapping_add(1);
+      self.read_cycle(next);
 
-      let jump_addr = self.program_counter
-        .wrapping_add(1)
-        .wrapping_add(offset as u16);
+      let jump_addr = next.wrapping_add(offset as u16);
 
-      if page_crossed(self.program_counter.wrapping_add(1), jump_addr) {
-        self.bus.tick(1);
+      // Only the low byte is updated at first, a page crossing fixes the high byte a cycle later
+      if page_crossed(next, jump_addr) {
+        self.read_cycle((next & 0xFF00) | (jump_addr & 0x00FF));
       }
 
       self.program_counter = jump_addr;
@@ -647,17 +1129,25 @@ impl<'a> CPU<'a> {
   }
 
   fn jsr(&mut self) {
+    let lo = self.read_cycle(self.program_counter);
+    self.stack_dummy_read();
+
     /* Subtract one to undo the PC increment from the opcode */
     self.stack_push_u16(self.program_counter + 2 - 1);
-    self.program_counter = self.mem_read_u16(self.program_counter);
+    let hi = self.read_cycle(self.program_counter.wrapping_add(1));
+    self.program_counter = u16::from_le_bytes([lo, hi]);
   }
 
   fn rts(&mut self) {
-    self.program_counter = self.stack_pop_u16() + 1;
+    self.stack_dummy_read();
+    let addr = self.stack_pop_u16();
+    self.read_cycle(addr);
+    self.program_counter = addr + 1;
   }
 
   fn rti(&mut self) {
-    self.plp();
+    self.stack_dummy_read();
+    self.pull_status();
     self.program_counter = self.stack_pop_u16();
   }
 
@@ -671,242 +1161,224 @@ impl<'a> CPU<'a> {
   }
 
   fn pla(&mut self) {
+    self.stack_dummy_read();
     let data = self.stack_pop();
     self.set_register_a(data);
   }
 
   fn plp(&mut self) {
+    self.stack_dummy_read();
+    self.pull_status();
+  }
+
+  fn pull_status(&mut self) {
     self.status = (self.stack_pop() & !F_BREAK) | (self.status & F_BREAK);
   }
 
   fn nop(&mut self, mode: &AddressingMode) {
     match mode {
       AddressingMode::NoneAddressing => {},
-      AddressingMode::Immediate => {},
       _ => {
         let addr = self.get_operand_address(mode);
-        self.mem_read(addr);
+        self.read_cycle(addr);
       },
     };
   }
 
-  fn interrupt_nmi(&mut self) {
+  fn interrupt(&mut self, vector: u16) {
+    // Two cycles reading the next opcode, which is then thrown away
+    self.read_cycle(self.program_counter);
+    self.read_cycle(self.program_counter);
+
     self.stack_push_u16(self.program_counter);
 
     // Push status with break flag set to 10
     self.stack_push((self.status | F_BREAK_BIT_5) & !F_BREAK_BIT_4);
     self.sei();
 
-    self.bus.tick(2); // NMI cycles
-    self.program_counter = self.mem_read_u16(0xFFFA);
+    self.program_counter = self.read_cycle_u16(vector);
   }
 
-  pub fn run(&mut self) {
-    self.run_with_callback(|_| {});
-  }
-
-  pub fn run_with_callback<F>(&mut self, mut callback: F)
-  where
-    F: FnMut(&mut CPU)
-  {
-    loop {
-      if let Some(_) = self.bus.poll_nmi_interrupt() {
-        self.interrupt_nmi();
-      }
-
-      callback(self);
-
-      // Fetch next instruction
-      let opcode = self.mem_read(self.program_counter);
-      self.program_counter += 1;
-      let program_counter_state = self.program_counter;
-
-      let op = OPS_MAP[&opcode];
-
-      match op.ins {
-        
-        "LDA" => self.lda(&op.mode),
-
-        "LDX" => self.ldx(&op.mode),
-
-        "LDY" => self.ldy(&op.mode),
-
-        "STA" => self.sta(&op.mode),
-
-        "STX" => self.stx(&op.mode),
-
-        "STY" => self.sty(&op.mode),
-
-        "TAX" => self.tax(),
-
-        "TAY" => self.tay(),
-
-        "TSX" => self.tsx(),
-
-        "TXA" => self.txa(),
-
-        "TXS" => self.txs(),
-
-        "TYA" => self.tya(),
-
-        "INC" => self.inc(&op.mode),
-
-        "INX" => self.inx(),
-
-        "INY" => self.iny(),
-
-        "DEC" => self.dec(&op.mode),
-
-        "DEX" => self.dex(),
-
-        "DEY" => self.dey(),
-
-        "ADC" => self.adc(&op.mode),
-
-        "SBC" => self.sbc(&op.mode),
-
-        "AND" => self.and(&op.mode),
-
-        "ORA" => self.or(&op.mode),
-
-        "EOR" => self.eor(&op.mode),
-
-        "CMP" => self.compare(&op.mode, self.register_a),
-
-        "CPX" => self.compare(&op.mode, self.register_x),
-
-        "CPY" => self.compare(&op.mode, self.register_y),
-
-        "ROL" => self.rol(&op.mode),
-
-        "ROR" => self.ror(&op.mode),
-
-        "ASL" => self.asl(&op.mode),
-
-        "LSR" => self.lsr(&op.mode),
-
-        "CLC" => self.clc(),
-
-        "CLD" => self.cld(),
-
-        "CLI" => self.cli(),
-
-        "CLV" => self.clv(),
-
-        "SEC" => self.sec(),
-
-        "SED" => self.sed(),
-
-        "SEI" => self.sei(),
-
-        "BCC" => self.branch(F_CARRY, false),
-
-        "BCS" => self.branch(F_CARRY, true),
-
-        "BNE" => self.branch(F_ZERO, false),
-
-        "BEQ" => self.branch(F_ZERO, true),
-
-        "BPL" => self.branch(F_NEG, false),
-
-        "BMI" => self.branch(F_NEG, true),
-
-        "BVC" => self.branch(F_OVRFLW, false),
-
-        "BVS" => self.branch(F_OVRFLW, true),
+  fn brk(&mut self) {
+    // The padding byte after BRK was already read, the return address skips it
+    self.program_counter = self.program_counter.wrapping_add(1);
+    self.stack_push_u16(self.program_counter);
 
-        "JMP" => {
-          match opcode {
-            /* Absolute */
-            0x4c => {
-              self.program_counter = self.mem_read_u16(self.program_counter);
-            },
+    // Push status with break flag set to 11
+    self.stack_push(self.status | F_BREAK);
+    self.sei();
 
-            /* Indirect */
-            0x6c => {
-              let addr = self.mem_read_u16(self.program_counter);
-              // self.program_counter = self.mem_read_u16(addr);
+    self.program_counter = self.read_cycle_u16(0xFFFE);
+  }
 
-              // 6502 bug mode with with page boundary:
-              //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
-              //  the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
-              //  i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
+  fn jmp_absolute(&mut self) {
+    self.program_counter = self.read_cycle_u16(self.program_counter);
+  }
 
-              let indirect_addr = if addr & 0x00FF == 0x00FF {
-                let lo = self.mem_read(addr);
-                let hi = self.mem_read(addr & 0xFF00);
-                (hi as u16) << 8 | (lo as u16)
-              } else {
-                self.mem_read_u16(addr)
-              };
+  fn jmp_indirect(&mut self) {
+    let addr = self.read_cycle_u16(self.program_counter);
+    // self.program_counter = self.mem_read_u16(addr);
 
-              self.program_counter = indirect_addr;
-            },
+    // 6502 bug mode with with page boundary:
+    //  if address $3000 contains $40, $30FF contains $80, and $3100 contains $50,
+    //  the result of JMP ($30FF) will be a transfer of control to $4080 rather than $5080 as you intended
+    //  i.e. the 6502 took the low byte of the address from $30FF and the high byte from $3000
 
-            _ => {},
-          }
-        },
+    let indirect_addr = if addr & 0x00FF == 0x00FF {
+      let lo = self.read_cycle(addr);
+      let hi = self.read_cycle(addr & 0xFF00);
+      (hi as u16) << 8 | (lo as u16)
+    } else {
+      self.read_cycle_u16(addr)
+    };
 
-        "JSR" => self.jsr(),
+    self.program_counter = indirect_addr;
+  }
 
-        "RTS" => self.rts(),
+  pub fn run(&mut self) {
+    self.run_with_callback(|_| {});
+  }
 
-        "BIT" => self.bit(&op.mode),
+  pub fn run_with_callback<F>(&mut self, mut callback: F)
+  where
+    F: FnMut(&mut Self)
+  {
+    loop {
+      self.poll_interrupts();
 
-        "RTI" => self.rti(),
+      callback(self);
 
-        "PHA" => self.pha(),
+      if !self.execute_instruction() {
+        return;
+      }
+    }
+  }
 
-        "PHP" => self.php(),
+  // The stepping functions return false when the CPU stopped on BRK or jammed
 
-        "PLA" => self.pla(),
+  // Services a pending interrupt and executes one instruction
+  pub fn step_instruction(&mut self) -> bool {
+    self.poll_interrupts();
+    self.execute_instruction()
+  }
 
-        "PLP" => self.plp(),
+  // Runs whole instructions until at least `cycles` CPU cycles have passed
+  pub fn step_cycles(&mut self, cycles: usize) -> bool {
+    let target = self.bus.cycles() + cycles;
+    while self.bus.cycles() < target {
+      if !self.step_instruction() {
+        return false;
+      }
+    }
+    true
+  }
 
-        "NOP" => {},
+  pub fn jammed(&self) -> bool {
+    self.jammed
+  }
 
-        "*NOP" => self.nop(&op.mode),
+  pub fn set_variant(&mut self, variant: Variant) {
+    self.variant = variant;
+  }
 
-        "*LAX" => self.lax(&op.mode),
+  pub fn variant(&self) -> Variant {
+    self.variant
+  }
 
-        "*SAX" => self.sax(&op.mode),
+  fn poll_interrupts(&mut self) {
+    if self.jammed {
+      return;
+    }
 
-        "*SBC" => self.sbc(&op.mode),
+    // A $2002 read can still have cancelled the NMI after it was sampled
+    if self.nmi_sampled && self.bus.poll_nmi_interrupt().is_some() {
+      self.nmi_sampled = false;
+      self.interrupt(0xFFFA);
+    } else if self.status & F_INT == 0 && self.bus.irq_pending() {
+      self.interrupt(0xFFFE);
+    }
+  }
 
-        "*DCP" => self.dcp(&op.mode),
+  fn execute_instruction(&mut self) -> bool {
+    if self.jammed {
+      return false;
+    }
 
-        "*ISB" => self.isb(&op.mode),
+    // Fetch next instruction
+    let opcode = self.read_cycle(self.program_counter);
+    self.program_counter += 1;
+    let program_counter_state = self.program_counter;
 
-        "*SLO" => self.slo(&op.mode),
+    let (op, handler) = match self.dispatch[opcode as usize] {
+      Some(entry) => entry,
+      None => panic!("unknown opcode: {}", opcode),
+    };
 
-        "*RLA" => self.rla(&op.mode),
+    // The NES programs and tests here end on BRK, so the 2A03 stops the run loop on it
+    if opcode == 0x00 && self.variant == Variant::Ricoh2A03 {
+      return false;
+    }
 
-        "*SRE" => self.sre(&op.mode),
+    // Every single byte instruction reads the byte after the opcode and throws it away
+    if op.len == 1 {
+      self.read_cycle(self.program_counter);
+    }
 
-        "*RRA" => self.rra(&op.mode),
+    handler(self, op);
 
-        "BRK" => return,
+    if self.jammed {
+      // Stays on the KIL opcode until reset
+      self.program_counter = program_counter_state - 1;
+      return false;
+    }
 
-        _ => panic!("unknown opcode: {}", opcode),
-      }
+    if program_counter_state == self.program_counter {
+      self.program_counter += (op.len - 1) as u16;
+    }
 
-      self.bus.tick(op.cycles);
+    true
+  }
+}
 
-      if program_counter_state == self.program_counter {
-        self.program_counter += (op.len - 1) as u16;
-      }
+impl<'a> Snapshot for CPU<'a> {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_u8(self.register_a);
+    w.write_u8(self.register_x);
+    w.write_u8(self.register_y);
+    w.write_u8(self.status);
+    w.write_u16(self.program_counter);
+    w.write_u8(self.stack_pointer);
+    w.write_bool(self.jammed);
+    w.write_bool(self.nmi_sampled);
+    w.write_u8(self.variant as u8);
+    self.bus.save(w);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    self.register_a = r.read_u8()?;
+    self.register_x = r.read_u8()?;
+    self.register_y = r.read_u8()?;
+    self.status = r.read_u8()?;
+    self.program_counter = r.read_u16()?;
+    self.stack_pointer = r.read_u8()?;
+    self.jammed = r.read_bool()?;
+    self.nmi_sampled = r.read_bool()?;
+    if r.read_u8()? != self.variant as u8 {
+      return Err("Save state uses a different CPU variant".to_string());
     }
+    self.bus.load(r)
   }
 }
 
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::flat_bus::FlatBus;
   use crate::rom::test;
 
   #[test]
   fn test_0xa9_lda_immediate_load_data() {
-    let mut cpu = CPU::new(test::test_rom());
+    let mut cpu = CPU::with_bus(FlatBus::new());
     cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
     assert_eq!(cpu.register_a, 0x05);
     assert!(cpu.status & F_ZERO == 0);
@@ -915,21 +1387,21 @@ mod test {
 
   #[test]
   fn test_0xa9_lda_zero_flag() {
-    let mut cpu = CPU::new(test::test_rom());
+    let mut cpu = CPU::with_bus(FlatBus::new());
     cpu.load_and_run(vec![0xa9, 0x00, 0x00]);
     assert!(cpu.status & F_ZERO != 0);
   }
 
   #[test]
   fn test_0xa9_lda_negative_flag() {
-    let mut cpu = CPU::new(test::test_rom());
+    let mut cpu = CPU::with_bus(FlatBus::new());
     cpu.load_and_run(vec![0xa9, 0xff, 0x00]);
     assert!(cpu.status & F_NEG != 0);
   }
 
   #[test]
   fn test_0xaa_tax() {
-    let mut cpu = CPU::new(test::test_rom());
+    let mut cpu = CPU::with_bus(FlatBus::new());
     cpu.load_and_run(vec![0xa9, 0x0a, 0xaa, 0x00]);
     assert_eq!(cpu.register_x, 10);
     assert!(cpu.status & F_ZERO == 0);
@@ -938,21 +1410,21 @@ mod test {
 
   #[test]
   fn test_0xaa_tax_zero_flag() {
-    let mut cpu = CPU::new(test::test_rom());
+    let mut cpu = CPU::with_bus(FlatBus::new());
     cpu.load_and_run(vec![0xa9, 0x00, 0x00]);
     assert!(cpu.status & F_ZERO != 0);
   }
 
   #[test]
   fn test_0xaa_tax_negative_flag() {
-    let mut cpu = CPU::new(test::test_rom());
+    let mut cpu = CPU::with_bus(FlatBus::new());
     cpu.load_and_run(vec![0xa9, 0xff, 0x00]);
     assert_ne!(cpu.status & F_NEG, 0);
   }
 
   #[test]
   fn test_5_ops_working_together() {
-      let mut cpu = CPU::new(test::test_rom());
+      let mut cpu = CPU::with_bus(FlatBus::new());
       cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
 
       assert_eq!(cpu.register_x, 0xc1)
@@ -960,7 +1432,7 @@ mod test {
 
    #[test]
    fn test_inx_overflow() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xff, 0xaa, 0xe8, 0x00]);
 
        assert_eq!(cpu.register_x, 0);
@@ -969,7 +1441,7 @@ mod test {
 
    #[test]
    fn test_lda_from_memory() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.mem_write(0x10, 0x55);
 
        cpu.load_and_run(vec![0xa5, 0x10, 0x00]);
@@ -979,7 +1451,7 @@ mod test {
 
    #[test]
    fn test_sta() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.mem_write(0x10, 0x55);
 
        cpu.load_and_run(vec![0xa5, 0x10, 0x85, 0x20, 0x00]);
@@ -990,14 +1462,14 @@ mod test {
 
    #[test]
    fn test_adc() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x05, 0x69, 0x01, 0x00]);
        assert_eq!(cpu.register_a, 0x6);
    }
 
    #[test]
    fn test_adc_with_carry() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x05, 0x38, 0x69, 0x01, 0x00]);
        assert_eq!(cpu.register_a, 0x7);
    }
@@ -1006,7 +1478,7 @@ mod test {
    // https://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
    #[test]
    fn test_adc_1() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x50, 0x69, 0x10, 0x00]);
        assert_eq!(cpu.register_a, 0x60);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1015,7 +1487,7 @@ mod test {
 
    #[test]
    fn test_adc_2() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x50, 0x69, 0x50, 0x00]);
        assert_eq!(cpu.register_a, 0xa0);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1024,7 +1496,7 @@ mod test {
 
    #[test]
    fn test_adc_3() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x50, 0x69, 0x90, 0x00]);
        assert_eq!(cpu.register_a, 0xe0);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1033,7 +1505,7 @@ mod test {
 
    #[test]
    fn test_adc_4() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x50, 0x69, 0xd0, 0x00]);
        assert_eq!(cpu.register_a, 0x20);
        assert_ne!(cpu.status & F_CARRY, 0);
@@ -1042,7 +1514,7 @@ mod test {
 
    #[test]
    fn test_adc_5() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xd0, 0x69, 0x10, 0x00]);
        assert_eq!(cpu.register_a, 0xe0);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1051,7 +1523,7 @@ mod test {
 
    #[test]
    fn test_adc_6() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xd0, 0x69, 0x50, 0x00]);
        assert_eq!(cpu.register_a, 0x20);
        assert_ne!(cpu.status & F_CARRY, 0);
@@ -1060,7 +1532,7 @@ mod test {
 
    #[test]
    fn test_adc_7() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xd0, 0x69, 0x90, 0x00]);
        assert_eq!(cpu.register_a, 0x60);
        assert_ne!(cpu.status & F_CARRY, 0);
@@ -1069,7 +1541,7 @@ mod test {
 
    #[test]
    fn test_adc_8() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xd0, 0x69, 0xd0, 0x00]);
        assert_eq!(cpu.register_a, 0xa0);
        assert_ne!(cpu.status & F_CARRY, 0);
@@ -1078,21 +1550,21 @@ mod test {
 
    #[test]
    fn test_sbc() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x05, 0xe9, 0x01, 0x00]);
        assert_eq!(cpu.register_a, 0x3);
    }
 
    #[test]
    fn test_sbc_with_carry() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x05, 0x38, 0xe9, 0x01, 0x00]);
        assert_eq!(cpu.register_a, 0x4);
    }
 
    #[test]
    fn test_sbc_1() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x50, 0xe9, 0xf0, 0x00]);
        assert_eq!(cpu.register_a, 0x5f);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1101,7 +1573,7 @@ mod test {
 
    #[test]
    fn test_sbc_2() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x50, 0xe9, 0xb0, 0x00]);
        assert_eq!(cpu.register_a, 0x9f);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1110,7 +1582,7 @@ mod test {
 
    #[test]
    fn test_sbc_3() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x50, 0xe9, 0x70, 0x00]);
        assert_eq!(cpu.register_a, 0xdf);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1119,7 +1591,7 @@ mod test {
 
    #[test]
    fn test_sbc_4() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x50, 0xe9, 0x30, 0x00]);
        assert_eq!(cpu.register_a, 0x1f);
        assert_ne!(cpu.status & F_CARRY, 0);
@@ -1128,7 +1600,7 @@ mod test {
 
    #[test]
    fn test_sbc_5() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xd0, 0x38, 0xe9, 0xf0, 0x00]);
        assert_eq!(cpu.register_a, 0xe0);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1137,7 +1609,7 @@ mod test {
 
    #[test]
    fn test_sbc_6() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xd0, 0x38, 0xe9, 0xb0, 0x00]);
        assert_eq!(cpu.register_a, 0x20);
        assert_ne!(cpu.status & F_CARRY, 0);
@@ -1146,7 +1618,7 @@ mod test {
 
    #[test]
    fn test_sbc_7() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xd0, 0x38, 0xe9, 0x70, 0x00]);
        assert_eq!(cpu.register_a, 0x60);
        assert_ne!(cpu.status & F_CARRY, 0);
@@ -1155,16 +1627,147 @@ mod test {
 
    #[test]
    fn test_sbc_8() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xd0, 0x38, 0xe9, 0x30, 0x00]);
        assert_eq!(cpu.register_a, 0xa0);
        assert_ne!(cpu.status & F_CARRY, 0);
        assert_eq!(cpu.status & F_OVRFLW, 0);
    }
 
+   // The NMOS CPU takes the BRK at the end as an interrupt, so both variants stop in front of it
+   fn run_decimal(variant: Variant, program: Vec<u8>) -> CPU<'static, FlatBus> {
+       let end = 0x0600 + program.len() as u16 - 1;
+       let mut cpu = CPU::with_bus(FlatBus::new());
+       cpu.set_variant(variant);
+       cpu.power_on();
+       cpu.load(program);
+       while cpu.program_counter != end {
+           cpu.step_instruction();
+       }
+       cpu
+   }
+
+   #[test]
+   fn test_decimal_mode_ignored_on_2a03() {
+       let cpu = run_decimal(Variant::Ricoh2A03, vec![0xf8, 0x18, 0xa9, 0x58, 0x69, 0x46, 0x00]);
+       assert_eq!(cpu.register_a, 0x9e);
+       assert_eq!(cpu.status & F_CARRY, 0);
+   }
+
+   #[test]
+   fn test_adc_decimal() {
+       // (A, M, carry in, A out, carry out)
+       let cases = [
+           (0x58, 0x46, false, 0x04, true),
+           (0x12, 0x34, false, 0x46, false),
+           (0x15, 0x26, false, 0x41, false),
+           (0x81, 0x92, false, 0x73, true),
+           (0x58, 0x46, true, 0x05, true),
+           (0x99, 0x00, true, 0x00, true),
+       ];
+       for (a, m, carry, result, carry_out) in cases {
+           let set_carry = if carry { 0x38 } else { 0x18 };
+           let cpu = run_decimal(Variant::Nmos6502, vec![0xf8, set_carry, 0xa9, a, 0x69, m, 0x00]);
+           assert_eq!(cpu.register_a, result, "{:02x} + {:02x}", a, m);
+           assert_eq!(cpu.status & F_CARRY != 0, carry_out, "{:02x} + {:02x}", a, m);
+       }
+   }
+
+   #[test]
+   fn test_adc_decimal_flags() {
+       // N and Z don't follow the BCD result on the NMOS 6502
+       let cpu = run_decimal(Variant::Nmos6502, vec![0xf8, 0x18, 0xa9, 0x99, 0x69, 0x01, 0x00]);
+       assert_eq!(cpu.register_a, 0x00);
+       assert_eq!(cpu.status & F_ZERO, 0);
+       assert_ne!(cpu.status & F_NEG, 0);
+       assert_ne!(cpu.status & F_CARRY, 0);
+
+       let cpu = run_decimal(Variant::Nmos6502, vec![0xf8, 0x18, 0xa9, 0x79, 0x69, 0x00, 0x00]);
+       assert_eq!(cpu.register_a, 0x79);
+       assert_eq!(cpu.status & F_OVRFLW, 0);
+
+       let cpu = run_decimal(Variant::Nmos6502, vec![0xf8, 0x18, 0xa9, 0x79, 0x69, 0x01, 0x00]);
+       assert_eq!(cpu.register_a, 0x80);
+       assert_ne!(cpu.status & F_OVRFLW, 0);
+   }
+
+   #[test]
+   fn test_sbc_decimal() {
+       // (A, M, carry in, A out, carry out)
+       let cases = [
+           (0x46, 0x12, true, 0x34, true),
+           (0x40, 0x13, true, 0x27, true),
+           (0x32, 0x02, false, 0x29, true),
+           (0x12, 0x21, true, 0x91, false),
+           (0x21, 0x34, true, 0x87, false),
+           (0x00, 0x01, true, 0x99, false),
+       ];
+       for (a, m, carry, result, carry_out) in cases {
+           let set_carry = if carry { 0x38 } else { 0x18 };
+           let cpu = run_decimal(Variant::Nmos6502, vec![0xf8, set_carry, 0xa9, a, 0xe9, m, 0x00]);
+           assert_eq!(cpu.register_a, result, "{:02x} - {:02x}", a, m);
+           assert_eq!(cpu.status & F_CARRY != 0, carry_out, "{:02x} - {:02x}", a, m);
+       }
+   }
+
+   #[test]
+   fn test_brk_nmos() {
+       // BRK, padding, INX and an RTI at the $8000 handler
+       let mut bus = FlatBus::new();
+       bus.load(0xfffe, &[0x00, 0x80]);
+       bus.load(0x8000, &[0x40]);
+       let mut cpu = CPU::with_bus(bus);
+       cpu.set_variant(Variant::Nmos6502);
+       cpu.power_on();
+       cpu.load(vec![0x00, 0xff, 0xe8]);
+       cpu.status &= !F_INT;
+
+       let cycles = cpu.bus.cycles();
+       assert!(cpu.step_instruction());
+       assert_eq!(cpu.bus.cycles() - cycles, 7);
+       assert_eq!(cpu.program_counter, 0x8000);
+       assert_ne!(cpu.status & F_INT, 0);
+       assert_eq!(cpu.mem_peek(0x01fd), 0x06);
+       assert_eq!(cpu.mem_peek(0x01fc), 0x02);
+       assert_eq!(cpu.mem_peek(0x01fb) & F_BREAK, F_BREAK);
+
+       cpu.step_instruction();
+       cpu.step_instruction();
+       assert_eq!(cpu.program_counter, 0x0603);
+       assert_eq!(cpu.register_x, 1);
+       assert_eq!(cpu.status & F_INT, 0);
+   }
+
+   #[test]
+   fn test_irq() {
+       // NOP, NOP with an INX, INX handler at $8000
+       let mut bus = FlatBus::new();
+       bus.load(0xfffe, &[0x00, 0x80]);
+       bus.load(0x8000, &[0xe8, 0xe8]);
+       let mut cpu = CPU::with_bus(bus);
+       cpu.power_on();
+       cpu.load(vec![0xea, 0xea]);
+
+       // Masked by the I flag that reset set
+       cpu.bus.set_irq(true);
+       cpu.step_instruction();
+       assert_eq!(cpu.program_counter, 0x0601);
+
+       cpu.status &= !F_INT;
+       cpu.step_instruction();
+       assert_eq!(cpu.program_counter, 0x8001);
+       assert_eq!(cpu.mem_peek(0x01fc), 0x01);
+       assert_eq!(cpu.mem_peek(0x01fb) & F_BREAK, F_BREAK_BIT_5);
+
+       // The handler runs with I set, so the held line doesn't interrupt it again
+       cpu.step_instruction();
+       assert_eq!(cpu.program_counter, 0x8002);
+       assert_eq!(cpu.register_x, 2);
+   }
+
    #[test]
    fn test_asl_acc_1() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x01, 0x0a, 0x00]);
        assert_eq!(cpu.register_a, 0x02);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1174,7 +1777,7 @@ mod test {
 
    #[test]
    fn test_asl_acc_2() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xf1, 0x0a, 0x00]);
        assert_eq!(cpu.register_a, 0xe2);
        assert_ne!(cpu.status & F_CARRY, 0);
@@ -1183,7 +1786,7 @@ mod test {
 
    #[test]
    fn test_asl_acc_3() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x7f, 0x0a, 0x00]);
        assert_eq!(cpu.register_a, 0xfe);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1192,7 +1795,7 @@ mod test {
 
    #[test]
    fn test_asl_acc_4() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x80, 0x0a, 0x00]);
        assert_eq!(cpu.register_a, 0);
        assert_ne!(cpu.status & F_CARRY, 0);
@@ -1202,7 +1805,7 @@ mod test {
 
    #[test]
    fn test_lsr_acc_1() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x01, 0x4a, 0x00]);
        assert_eq!(cpu.register_a, 0);
        assert_eq!(cpu.status & F_NEG, 0);
@@ -1212,7 +1815,7 @@ mod test {
 
    #[test]
    fn test_lsr_acc_2() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x81, 0x4a, 0x00]);
        assert_eq!(cpu.register_a, 0x40);
        assert_eq!(cpu.status & F_NEG, 0);
@@ -1222,7 +1825,7 @@ mod test {
 
    #[test]
    fn test_lsr_acc_3() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x82, 0x4a, 0x00]);
        assert_eq!(cpu.register_a, 0x41);
        assert_eq!(cpu.status & F_CARRY, 0);
@@ -1230,21 +1833,21 @@ mod test {
 
    #[test]
    fn test_pha_pla() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0x11, 0x48, 0xa9, 0x22, 0x68, 0x00]);
        assert_eq!(cpu.register_a, 0x11);
    }
 
    #[test]
    fn test_pha_plp() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        cpu.load_and_run(vec![0xa9, 0xff, 0x48, 0x28, 0x00]);
        assert_eq!(cpu.status & !F_BREAK, !F_BREAK);
    }
 
    #[test]
    fn test_jsr_rts() {
-       let mut cpu = CPU::new(test::test_rom());
+       let mut cpu = CPU::with_bus(FlatBus::new());
        /*
           JSR init
           JSR loop
@@ -1269,4 +1872,292 @@ mod test {
        assert_ne!(cpu.status & F_ZERO, 0);
        assert_ne!(cpu.status & F_CARRY, 0);
    }
+
+   fn save_state_program() -> CPU<'static> {
+       let mut cpu = CPU::new(test::test_rom());
+       cpu.power_on();
+       /*
+          LDA #$20
+          STA $2006
+          LDA #$00
+          STA $2006
+          LDY #$08
+        outer:
+          LDX #$00
+        inner:
+          STX $2007
+          STA $10,X
+          LDA $2002
+          INX
+          BNE inner
+          DEY
+          BNE outer
+          BRK
+        */
+       cpu.load(vec![
+           0xa9, 0x20, 0x8d, 0x06, 0x20, 0xa9, 0x00, 0x8d, 0x06, 0x20, 0xa0, 0x08, 0xa2, 0x00,
+           0x8e, 0x07, 0x20, 0x95, 0x10, 0xad, 0x02, 0x20, 0xe8, 0xd0, 0xf5, 0x88, 0xd0, 0xf0, 0x00,
+       ]);
+       cpu.program_counter = 0x600;
+       cpu
+   }
+
+   #[test]
+   fn test_save_state_round_trip() {
+       use crate::trace::{trace_with_format, TraceFormat};
+
+       let mut cpu = save_state_program();
+       let mut state = vec![];
+       let mut expected: Vec<String> = vec![];
+       let mut count = 0;
+       cpu.run_with_callback(|cpu| {
+           count += 1;
+           if count == 3000 {
+               state = cpu.save_state();
+           }
+           if count >= 3000 {
+               expected.push(trace_with_format(cpu, TraceFormat::Timing));
+           }
+       });
+       let expected_end = cpu.save_state();
+
+       let mut cpu = CPU::new(test::test_rom());
+       cpu.load_state(&state).unwrap();
+       let mut result: Vec<String> = vec![];
+       cpu.run_with_callback(|cpu| {
+           result.push(trace_with_format(cpu, TraceFormat::Timing));
+       });
+
+       assert_eq!(result, expected);
+       assert_eq!(cpu.save_state(), expected_end);
+   }
+
+   #[test]
+   fn test_load_state_err() {
+       let mut cpu = save_state_program();
+       let state = cpu.save_state();
+
+       assert!(cpu.load_state(&state[..state.len() - 1]).is_err());
+       assert_eq!(cpu.save_state(), state);
+
+       let mut other_rom = test::test_rom();
+       other_rom.prg_rom[0] = 0xff;
+       let mut other = CPU::new(other_rom);
+       assert_eq!(other.load_state(&state), Err("Save state belongs to a different ROM".to_string()));
+
+       cpu.set_variant(Variant::Nmos6502);
+       assert_eq!(cpu.load_state(&state), Err("Save state uses a different CPU variant".to_string()));
+   }
+
+   #[test]
+   fn test_stepping() {
+       let mut cpu = CPU::new(test::test_rom());
+       cpu.power_on();
+       // LDX #$00, INX, JMP $0602, then BRK at $0606
+       cpu.load(vec![0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x06]);
+       cpu.program_counter = 0x600;
+
+       assert!(cpu.step_instruction());
+       assert_eq!(cpu.program_counter, 0x602);
+
+       let cycles = cpu.bus.cycles();
+       assert!(cpu.step_cycles(10));
+       // INX (2) and JMP (3) until at least 10 cycles have passed
+       assert_eq!(cpu.bus.cycles() - cycles, 10);
+       assert_eq!(cpu.register_x, 2);
+
+       assert!(cpu.run_frame());
+       assert_eq!(cpu.bus.ppu().scanline(), 241);
+       assert!(cpu.run_frame());
+       assert_eq!(cpu.bus.ppu().scanline(), 241);
+
+       cpu.program_counter = 0x606;
+       assert!(!cpu.step_cycles(100));
+       assert_eq!(cpu.program_counter, 0x607);
+   }
+
+   #[test]
+   fn test_reset_keeps_ram_and_registers() {
+       let mut cpu = CPU::new(test::test_rom());
+       cpu.power_on();
+       assert_eq!(cpu.stack_pointer, 0xfd);
+       assert_eq!(cpu.status, 0x24);
+
+       cpu.register_a = 0x42;
+       cpu.status = F_CARRY;
+       cpu.mem_write(0x10, 0x55);
+       cpu.reset();
+
+       assert_eq!(cpu.register_a, 0x42);
+       assert_eq!(cpu.stack_pointer, 0xfa);
+       assert_eq!(cpu.status, F_CARRY | F_INT);
+       assert_eq!(cpu.mem_read(0x10), 0x55);
+
+       cpu.power_on();
+       assert_eq!(cpu.register_a, 0);
+       assert_eq!(cpu.stack_pointer, 0xfd);
+       assert_eq!(cpu.mem_read(0x10), 0);
+   }
+
+   fn step_program(cpu: &mut CPU, program: Vec<u8>) -> bool {
+       cpu.load(program);
+       cpu.program_counter = 0x600;
+       cpu.step_instruction()
+   }
+
+   #[test]
+   fn test_unofficial_immediate_ops() {
+       let mut cpu = CPU::new(test::test_rom());
+       cpu.power_on();
+
+       // ALR #$03
+       cpu.register_a = 0xff;
+       step_program(&mut cpu, vec![0x4b, 0x03]);
+       assert_eq!(cpu.register_a, 0x01);
+       assert_eq!(cpu.status & F_CARRY, F_CARRY);
+
+       // ANC #$80
+       cpu.register_a = 0xff;
+       cpu.status &= !F_CARRY;
+       step_program(&mut cpu, vec![0x0b, 0x80]);
+       assert_eq!(cpu.register_a, 0x80);
+       assert_eq!(cpu.status & (F_CARRY | F_NEG), F_CARRY | F_NEG);
+
+       // ARR #$80
+       cpu.register_a = 0xff;
+       cpu.status &= !F_CARRY;
+       step_program(&mut cpu, vec![0x6b, 0x80]);
+       assert_eq!(cpu.register_a, 0x40);
+       assert_eq!(cpu.status & (F_CARRY | F_OVRFLW), F_CARRY | F_OVRFLW);
+
+       // AXS #$10
+       cpu.register_a = 0x0f;
+       cpu.register_x = 0xff;
+       step_program(&mut cpu, vec![0xcb, 0x10]);
+       assert_eq!(cpu.register_x, 0xff);
+       assert_eq!(cpu.status & (F_CARRY | F_NEG), F_NEG);
+
+       // XAA #$ff and LXA #$0f
+       cpu.register_a = 0x00;
+       step_program(&mut cpu, vec![0x8b, 0xff]);
+       assert_eq!(cpu.register_a, 0xee);
+       cpu.register_a = 0x00;
+       step_program(&mut cpu, vec![0xab, 0x0f]);
+       assert_eq!((cpu.register_a, cpu.register_x), (0x0e, 0x0e));
+
+       // LAS $0010,Y
+       cpu.register_y = 0;
+       cpu.stack_pointer = 0xfd;
+       cpu.mem_write(0x10, 0xf3);
+       step_program(&mut cpu, vec![0xbb, 0x10, 0x00]);
+       assert_eq!((cpu.register_a, cpu.register_x, cpu.stack_pointer), (0xf1, 0xf1, 0xf1));
+   }
+
+   #[test]
+   fn test_unofficial_high_byte_stores() {
+       let mut cpu = CPU::new(test::test_rom());
+       cpu.power_on();
+
+       // SHY $0210,X stores Y & $03
+       cpu.register_x = 0x01;
+       cpu.register_y = 0xff;
+       step_program(&mut cpu, vec![0x9c, 0x10, 0x02]);
+       assert_eq!(cpu.mem_read(0x211), 0x03);
+
+       // SHX $02ff,Y crosses a page, so the stored value becomes the high byte of the address
+       cpu.register_x = 0x05;
+       cpu.register_y = 0x01;
+       step_program(&mut cpu, vec![0x9e, 0xff, 0x02]);
+       assert_eq!(cpu.mem_read(0x100), 0x01);
+       assert_eq!(cpu.mem_read(0x300), 0x00);
+
+       // TAS $0210,Y
+       cpu.register_a = 0x3c;
+       cpu.register_x = 0xf6;
+       cpu.register_y = 0x00;
+       step_program(&mut cpu, vec![0x9b, 0x10, 0x02]);
+       assert_eq!(cpu.stack_pointer, 0x34);
+       assert_eq!(cpu.mem_read(0x210), 0x00);
+   }
+
+   #[test]
+   fn test_jam() {
+       let mut cpu = CPU::new(test::test_rom());
+       cpu.power_on();
+       // INX, KIL, INX
+       cpu.load(vec![0xe8, 0x02, 0xe8]);
+       cpu.program_counter = 0x600;
+
+       assert!(cpu.step_instruction());
+       assert!(!cpu.step_instruction());
+       assert!(cpu.jammed());
+       assert_eq!(cpu.program_counter, 0x601);
+
+       assert!(!cpu.step_cycles(100));
+       assert_eq!(cpu.register_x, 1);
+       assert_eq!(cpu.program_counter, 0x601);
+
+       let state = cpu.save_state();
+       cpu.reset();
+       assert!(!cpu.jammed());
+       cpu.load_state(&state).unwrap();
+       assert!(cpu.jammed());
+   }
+
+   #[test]
+   fn test_cycles_per_access() {
+       let mut cpu = CPU::new(test::test_rom());
+       cpu.power_on();
+       let cycles = |cpu: &mut CPU, x: u8, program: Vec<u8>| {
+           cpu.register_x = x;
+           let before = cpu.bus.cycles();
+           step_program(cpu, program);
+           cpu.bus.cycles() - before
+       };
+
+       // LDA $02ff,X only pays for the dummy read when the page is crossed
+       assert_eq!(cycles(&mut cpu, 0, vec![0xbd, 0xff, 0x02]), 4);
+       assert_eq!(cycles(&mut cpu, 1, vec![0xbd, 0xff, 0x02]), 5);
+       // STA $0200,X and INC $0200,X always do
+       assert_eq!(cycles(&mut cpu, 0, vec![0x9d, 0x00, 0x02]), 5);
+       assert_eq!(cycles(&mut cpu, 0, vec![0xfe, 0x00, 0x02]), 7);
+       // STA ($10),Y
+       assert_eq!(cycles(&mut cpu, 0, vec![0x91, 0x10]), 6);
+       // PHA, PLA, JSR $0600, RTS
+       assert_eq!(cycles(&mut cpu, 0, vec![0x48]), 3);
+       assert_eq!(cycles(&mut cpu, 0, vec![0x68]), 4);
+       assert_eq!(cycles(&mut cpu, 0, vec![0x20, 0x00, 0x06]), 6);
+       assert_eq!(cycles(&mut cpu, 0, vec![0x60]), 6);
+       // BNE not taken, taken, and taken across a page
+       cpu.status |= F_ZERO;
+       assert_eq!(cycles(&mut cpu, 0, vec![0xd0, 0x02]), 2);
+       cpu.status &= !F_ZERO;
+       assert_eq!(cycles(&mut cpu, 0, vec![0xd0, 0x02]), 3);
+       assert_eq!(cycles(&mut cpu, 0, vec![0xd0, 0x80]), 4);
+   }
+
+   #[test]
+   fn test_nmi_enabled_in_vblank_waits_an_instruction() {
+       let mut cpu = CPU::new(test::test_rom());
+       cpu.power_on();
+       // LDA #$80, STA $2000, INX, INX
+       cpu.load(vec![0xa9, 0x80, 0x8d, 0x00, 0x20, 0xe8, 0xe8]);
+       while cpu.bus.ppu().peek_status() & 0x80 == 0 {
+           cpu.bus.tick(1);
+       }
+       cpu.program_counter = 0x600;
+
+       assert!(cpu.step_instruction());
+       assert!(cpu.step_instruction());
+       assert!(cpu.bus.nmi_pending());
+
+       // The first INX still runs, the NMI is taken before the second
+       assert!(cpu.step_instruction());
+       assert_eq!(cpu.register_x, 1);
+       let stack_pointer = cpu.stack_pointer;
+       cpu.step_instruction();
+       assert_eq!(cpu.register_x, 1);
+       assert_eq!(cpu.stack_pointer, stack_pointer.wrapping_sub(3));
+       assert_eq!(cpu.mem_read_u16(0x100 + stack_pointer.wrapping_sub(1) as u16), 0x606);
+   }
 }
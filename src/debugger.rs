@@ -0,0 +1,520 @@
+use crate::cpu::AddressingMode;
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::disasm;
+use crate::ops::Op;
+use crate::ops::OPS_MAP;
+use crate::trace::trace;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::io::Write;
+
+const HISTORY_LEN: usize = 4;
+const DISASM_LINES: u16 = 6;
+// Status bits from 7 to 0, '-' marks the unused bit and 'b' the break flag
+const FLAG_NAMES: &str = "nv-bdizc";
+
+const HELP: &str = "\
+  c, continue             resume execution
+  s, step [n]             execute n instructions (default 1)
+  n, next                 step over JSR
+  o, out                  run until the current subroutine returns
+  b, break <addr>         break when pc reaches addr
+  w, watch <r|w|rw> <addr>  break on memory read and/or write at addr
+  op <opcode>             break before executing opcode
+  d, delete <addr|op>     remove breakpoints at addr (or on opcode with op)
+  l, list                 list breakpoints
+  r, regs                 show registers
+  set <a|x|y|p|sp|pc> <v> set register
+  flag <nvdizc> <0|1>     set or clear status flag
+  dis [addr] [n]          disassemble n instructions at addr (default pc)
+  x <addr> [n]            dump n bytes of memory at addr (default 16)
+  q, quit                 exit the emulator";
+
+#[derive(Debug, PartialEq)]
+enum StepMode {
+  Run,
+  Step(usize),
+  StepOver(u16),
+  StepOut(u8),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+  Prompt,
+  Resume,
+  Quit,
+}
+
+pub struct Debugger {
+  breakpoints: HashSet<u16>,
+  read_watchpoints: HashSet<u16>,
+  write_watchpoints: HashSet<u16>,
+  opcode_breakpoints: HashSet<u8>,
+  mode: StepMode,
+  history: VecDeque<u16>,
+  // Opcode of the instruction that just ran, step out waits for an RTS or RTI
+  last_opcode: Option<u8>,
+}
+
+fn parse_number(arg: Option<&str>) -> Result<u16, String> {
+  let arg = arg.ok_or_else(|| "missing argument".to_string())?;
+  let digits = arg.trim_start_matches('$').trim_start_matches("0x");
+  u16::from_str_radix(digits, 16).map_err(|_| format!("invalid hex value: {}", arg))
+}
+
+fn parse_byte(arg: Option<&str>) -> Result<u8, String> {
+  let value = parse_number(arg)?;
+  if value > 0xff {
+    return Err(format!("value out of range: {:x}", value));
+  }
+  Ok(value as u8)
+}
+
+pub fn format_instruction(cpu: &CPU, addr: u16) -> (String, u16) {
+  let bytes: Vec<u8> = (0..3).map(|i| cpu.mem_peek(addr.wrapping_add(i))).collect();
+  let ins = disasm::decode(&bytes, addr);
+  (disasm::format_instruction(&ins, &disasm::address), ins.bytes.len() as u16)
+}
+
+// Address the upcoming instruction will read and/or write through its addressing mode
+pub fn memory_access(cpu: &CPU, op: &Op) -> Option<(u16, bool, bool)> {
+  match op.mode {
+    AddressingMode::Immediate | AddressingMode::NoneAddressing => None,
+    _ => {
+      let addr = cpu.peek_absolute_address(&op.mode, cpu.program_counter.wrapping_add(1));
+      match op.ins {
+        "STA" | "STX" | "STY" | "*SAX" => Some((addr, false, true)),
+        "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC"
+        | "*DCP" | "*ISB" | "*SLO" | "*RLA" | "*SRE" | "*RRA" => Some((addr, true, true)),
+        _ => Some((addr, true, false)),
+      }
+    }
+  }
+}
+
+impl Default for Debugger {
+  fn default() -> Self {
+    Debugger::new()
+  }
+}
+
+impl Debugger {
+  pub fn new() -> Self {
+    Debugger {
+      breakpoints: HashSet::new(),
+      read_watchpoints: HashSet::new(),
+      write_watchpoints: HashSet::new(),
+      opcode_breakpoints: HashSet::new(),
+      // Stop before the first instruction so breakpoints can be set up
+      mode: StepMode::Step(1),
+      history: VecDeque::with_capacity(HISTORY_LEN),
+      last_opcode: None,
+    }
+  }
+
+  pub fn add_breakpoint(&mut self, addr: u16) {
+    self.breakpoints.insert(addr);
+  }
+
+  pub fn add_watchpoint(&mut self, addr: u16, read: bool, write: bool) {
+    if read {
+      self.read_watchpoints.insert(addr);
+    }
+    if write {
+      self.write_watchpoints.insert(addr);
+    }
+  }
+
+  pub fn add_opcode_breakpoint(&mut self, opcode: u8) {
+    self.opcode_breakpoints.insert(opcode);
+  }
+
+  // Callback for CPU::run_with_callback, enters the stdin REPL when a break condition is hit
+  pub fn on_instruction(&mut self, cpu: &mut CPU) {
+    if let Some(reason) = self.check_break(cpu) {
+      println!("{}", reason);
+      self.print_location(cpu);
+      self.repl(cpu);
+    }
+
+    if self.history.len() == HISTORY_LEN {
+      self.history.pop_front();
+    }
+    self.history.push_back(cpu.program_counter);
+  }
+
+  pub fn check_break(&mut self, cpu: &CPU) -> Option<String> {
+    let pc = cpu.program_counter;
+    let last_opcode = self.last_opcode.replace(cpu.mem_peek(pc));
+
+    let reason = match self.mode {
+      StepMode::Step(1) => Some(String::from("step")),
+      StepMode::Step(n) => {
+        self.mode = StepMode::Step(n - 1);
+        None
+      }
+      StepMode::StepOver(addr) if addr == pc => Some(String::from("step over")),
+      // A PLA or PLP also raises the stack pointer, only a return leaves the subroutine
+      StepMode::StepOut(sp) if matches!(last_opcode, Some(0x40 | 0x60)) && cpu.stack_pointer > sp => {
+        Some(String::from("step out"))
+      }
+      _ => None,
+    };
+
+    let reason = reason.or_else(|| self.check_breakpoints(cpu));
+    if reason.is_some() {
+      self.mode = StepMode::Run;
+    }
+    reason
+  }
+
+  fn check_breakpoints(&self, cpu: &CPU) -> Option<String> {
+    let pc = cpu.program_counter;
+    if self.breakpoints.contains(&pc) {
+      return Some(format!("breakpoint at ${:04X}", pc));
+    }
+
+    let opcode = cpu.mem_peek(pc);
+    if self.opcode_breakpoints.contains(&opcode) {
+      return Some(format!("opcode ${:02X} breakpoint at ${:04X}", opcode, pc));
+    }
+
+    let op = OPS_MAP.get(&opcode)?;
+    match memory_access(cpu, op) {
+      Some((addr, true, _)) if self.read_watchpoints.contains(&addr) => {
+        Some(format!("read watchpoint at ${:04X}", addr))
+      }
+      Some((addr, _, true)) if self.write_watchpoints.contains(&addr) => {
+        Some(format!("write watchpoint at ${:04X}", addr))
+      }
+      _ => None,
+    }
+  }
+
+  fn print_location(&self, cpu: &CPU) {
+    println!("{}", trace(cpu));
+    for addr in self.history.iter() {
+      println!("    {}", format_instruction(cpu, *addr).0);
+    }
+    let mut addr = cpu.program_counter;
+    for i in 0..DISASM_LINES {
+      let (line, len) = format_instruction(cpu, addr);
+      println!("{}   {}", if i == 0 { "=>" } else { "  " }, line);
+      addr = addr.wrapping_add(len);
+    }
+  }
+
+  fn repl(&mut self, cpu: &mut CPU) {
+    let stdin = std::io::stdin();
+    loop {
+      print!("(dbg) ");
+      std::io::stdout().flush().unwrap();
+
+      let mut line = String::new();
+      if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+        std::process::exit(0);
+      }
+
+      match self.execute(cpu, &line) {
+        Ok(Action::Prompt) => {},
+        Ok(Action::Resume) => return,
+        Ok(Action::Quit) => std::process::exit(0),
+        Err(e) => println!("error: {}", e),
+      }
+    }
+  }
+
+  pub fn execute(&mut self, cpu: &mut CPU, line: &str) -> Result<Action, String> {
+    let mut args = line.split_whitespace();
+    let command = match args.next() {
+      Some(command) => command,
+      None => return Ok(Action::Prompt),
+    };
+
+    match command {
+      "c" | "continue" => {
+        self.mode = StepMode::Run;
+        Ok(Action::Resume)
+      }
+      "s" | "step" => {
+        let count = match args.next() {
+          Some(n) => n.parse::<usize>().map_err(|_| format!("invalid count: {}", n))?,
+          None => 1,
+        };
+        // The current instruction executes before the next check
+        self.mode = StepMode::Step(count.max(1));
+        Ok(Action::Resume)
+      }
+      "n" | "next" => {
+        let pc = cpu.program_counter;
+        self.mode = match cpu.mem_peek(pc) {
+          0x20 => StepMode::StepOver(pc.wrapping_add(3)),
+          _ => StepMode::Step(1),
+        };
+        Ok(Action::Resume)
+      }
+      "o" | "out" => {
+        self.mode = StepMode::StepOut(cpu.stack_pointer);
+        Ok(Action::Resume)
+      }
+      "b" | "break" => {
+        self.add_breakpoint(parse_number(args.next())?);
+        Ok(Action::Prompt)
+      }
+      "w" | "watch" => {
+        let (read, write) = match args.next() {
+          Some("r") => (true, false),
+          Some("w") => (false, true),
+          Some("rw") => (true, true),
+          _ => return Err(String::from("expected r, w or rw")),
+        };
+        self.add_watchpoint(parse_number(args.next())?, read, write);
+        Ok(Action::Prompt)
+      }
+      "op" => {
+        self.add_opcode_breakpoint(parse_byte(args.next())?);
+        Ok(Action::Prompt)
+      }
+      "d" | "delete" => {
+        match args.next() {
+          Some("op") => {
+            self.opcode_breakpoints.remove(&parse_byte(args.next())?);
+          }
+          arg => {
+            let addr = parse_number(arg)?;
+            self.breakpoints.remove(&addr);
+            self.read_watchpoints.remove(&addr);
+            self.write_watchpoints.remove(&addr);
+          }
+        }
+        Ok(Action::Prompt)
+      }
+      "l" | "list" => {
+        for addr in self.breakpoints.iter() {
+          println!("break ${:04X}", addr);
+        }
+        for addr in self.read_watchpoints.iter() {
+          println!("watch r ${:04X}", addr);
+        }
+        for addr in self.write_watchpoints.iter() {
+          println!("watch w ${:04X}", addr);
+        }
+        for opcode in self.opcode_breakpoints.iter() {
+          println!("op ${:02X}", opcode);
+        }
+        Ok(Action::Prompt)
+      }
+      "r" | "regs" => {
+        println!(
+          "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+          cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer, cpu.program_counter,
+        );
+        let flags: String = FLAG_NAMES
+          .chars()
+          .enumerate()
+          .map(|(i, name)| match cpu.status & (0x80 >> i) {
+            0 => name,
+            _ => name.to_ascii_uppercase(),
+          })
+          .collect();
+        println!("{}", flags);
+        Ok(Action::Prompt)
+      }
+      "set" => {
+        let register = args.next().unwrap_or("");
+        match register {
+          "a" => cpu.register_a = parse_byte(args.next())?,
+          "x" => cpu.register_x = parse_byte(args.next())?,
+          "y" => cpu.register_y = parse_byte(args.next())?,
+          "p" => cpu.status = parse_byte(args.next())?,
+          "sp" => cpu.stack_pointer = parse_byte(args.next())?,
+          "pc" => cpu.program_counter = parse_number(args.next())?,
+          _ => return Err(format!("unknown register: {}", register)),
+        }
+        Ok(Action::Prompt)
+      }
+      "flag" => {
+        let name = args.next().unwrap_or("");
+        let bit = match FLAG_NAMES.find(name) {
+          Some(i) if name.len() == 1 && name != "-" && name != "b" => 0x80 >> i,
+          _ => return Err(format!("unknown flag: {}", name)),
+        };
+        match args.next() {
+          Some("1") => cpu.status |= bit,
+          Some("0") => cpu.status &= !bit,
+          _ => return Err(String::from("expected 0 or 1")),
+        }
+        Ok(Action::Prompt)
+      }
+      "dis" => {
+        let mut addr = match args.next() {
+          Some(arg) => parse_number(Some(arg))?,
+          None => cpu.program_counter,
+        };
+        let count = match args.next() {
+          Some(n) => n.parse::<u16>().map_err(|_| format!("invalid count: {}", n))?,
+          None => DISASM_LINES,
+        };
+        for _ in 0..count {
+          let (line, len) = format_instruction(cpu, addr);
+          println!("{}", line);
+          addr = addr.wrapping_add(len);
+        }
+        Ok(Action::Prompt)
+      }
+      "x" => {
+        let addr = parse_number(args.next())?;
+        let count = match args.next() {
+          Some(n) => n.parse::<u16>().map_err(|_| format!("invalid count: {}", n))?,
+          None => 16,
+        };
+        for row in (0..count).step_by(16) {
+          let start = addr.wrapping_add(row);
+          let bytes: Vec<String> = (row..count.min(row + 16))
+            .map(|i| format!("{:02X}", cpu.mem_peek(addr.wrapping_add(i))))
+            .collect();
+          println!("{:04X}  {}", start, bytes.join(" "));
+        }
+        Ok(Action::Prompt)
+      }
+      "h" | "help" => {
+        println!("{}", HELP);
+        Ok(Action::Prompt)
+      }
+      "q" | "quit" => Ok(Action::Quit),
+      _ => Err(format!("unknown command: {}", command)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::rom::test::test_rom;
+
+  fn test_cpu(program: &[u8]) -> CPU<'static> {
+    let mut cpu = CPU::new(test_rom());
+    cpu.power_on();
+    for (i, byte) in program.iter().enumerate() {
+      cpu.mem_write(0x600 + i as u16, *byte);
+    }
+    cpu.program_counter = 0x600;
+    cpu
+  }
+
+  // Runs until BRK and returns the pc of every break
+  fn breaks(cpu: &mut CPU, debugger: &mut Debugger, commands: &[&str]) -> Vec<u16> {
+    let mut commands = commands.iter();
+    let mut result = vec![];
+    cpu.run_with_callback(|cpu| {
+      if debugger.check_break(cpu).is_some() {
+        result.push(cpu.program_counter);
+        let command = commands.next().unwrap_or(&"c");
+        assert_eq!(debugger.execute(cpu, command), Ok(Action::Resume));
+      }
+    });
+    result
+  }
+
+  #[test]
+  fn test_format_instruction() {
+    // LDA #$01, STA $0200,X, BNE -4, JMP ($1234), ASL A
+    let cpu = test_cpu(&[0xa9, 0x01, 0x9d, 0x00, 0x02, 0xd0, 0xfc, 0x6c, 0x34, 0x12, 0x0a]);
+
+    assert_eq!(format_instruction(&cpu, 0x600), (String::from("0600  A9 01      LDA #$01"), 2));
+    assert_eq!(format_instruction(&cpu, 0x602), (String::from("0602  9D 00 02   STA $0200,X"), 3));
+    assert_eq!(format_instruction(&cpu, 0x605), (String::from("0605  D0 FC      BNE $0603"), 2));
+    assert_eq!(format_instruction(&cpu, 0x607), (String::from("0607  6C 34 12   JMP ($1234)"), 3));
+    assert_eq!(format_instruction(&cpu, 0x60a), (String::from("060A  0A         ASL A"), 1));
+  }
+
+  #[test]
+  fn test_breakpoints() {
+    // LDA #$01, STA $10, LDX $10, INX, BRK
+    let mut cpu = test_cpu(&[0xa9, 0x01, 0x85, 0x10, 0xa6, 0x10, 0xe8, 0x00]);
+    let mut debugger = Debugger::new();
+    debugger.mode = StepMode::Run;
+    debugger.add_breakpoint(0x606);
+    debugger.add_watchpoint(0x10, false, true);
+    debugger.add_watchpoint(0x10, true, false);
+
+    assert_eq!(breaks(&mut cpu, &mut debugger, &[]), vec![0x602, 0x604, 0x606]);
+  }
+
+  #[test]
+  fn test_opcode_breakpoint() {
+    // INX, INX, INY, INX, BRK
+    let mut cpu = test_cpu(&[0xe8, 0xe8, 0xc8, 0xe8, 0x00]);
+    let mut debugger = Debugger::new();
+    debugger.mode = StepMode::Run;
+    debugger.execute(&mut cpu, "op c8").unwrap();
+
+    assert_eq!(breaks(&mut cpu, &mut debugger, &[]), vec![0x602]);
+  }
+
+  #[test]
+  fn test_step_over_and_out() {
+    /*
+        JSR sub
+        INX
+        BRK
+      sub:
+        INY
+        INY
+        RTS
+    */
+    let program = [0x20, 0x05, 0x06, 0xe8, 0x00, 0xc8, 0xc8, 0x60];
+
+    let mut cpu = test_cpu(&program);
+    let mut debugger = Debugger::new();
+    assert_eq!(breaks(&mut cpu, &mut debugger, &["n", "c"]), vec![0x600, 0x603]);
+    assert_eq!(cpu.register_y, 2);
+
+    let mut cpu = test_cpu(&program);
+    let mut debugger = Debugger::new();
+    assert_eq!(breaks(&mut cpu, &mut debugger, &["s", "o", "c"]), vec![0x600, 0x605, 0x603]);
+
+    let mut cpu = test_cpu(&program);
+    let mut debugger = Debugger::new();
+    assert_eq!(breaks(&mut cpu, &mut debugger, &["s 3", "c"]), vec![0x600, 0x607]);
+  }
+
+  #[test]
+  fn test_step_out_past_pull() {
+    /*
+        JSR sub
+        INX
+        BRK
+      sub:
+        PHA
+        INY
+        PLA
+        RTS
+    */
+    let program = [0x20, 0x05, 0x06, 0xe8, 0x00, 0x48, 0xc8, 0x68, 0x60];
+
+    // Stepping out after the PHA runs past the PLA to the return
+    let mut cpu = test_cpu(&program);
+    let mut debugger = Debugger::new();
+    assert_eq!(breaks(&mut cpu, &mut debugger, &["s", "s", "o", "c"]), vec![0x600, 0x605, 0x606, 0x603]);
+  }
+
+  #[test]
+  fn test_edit_registers() {
+    let mut cpu = test_cpu(&[0x00]);
+    let mut debugger = Debugger::new();
+
+    assert_eq!(debugger.execute(&mut cpu, "set a $42"), Ok(Action::Prompt));
+    assert_eq!(debugger.execute(&mut cpu, "set pc 0x8000"), Ok(Action::Prompt));
+    assert_eq!(debugger.execute(&mut cpu, "flag c 1"), Ok(Action::Prompt));
+    assert_eq!(debugger.execute(&mut cpu, "flag i 0"), Ok(Action::Prompt));
+    assert!(debugger.execute(&mut cpu, "flag b 1").is_err());
+    assert!(debugger.execute(&mut cpu, "set a 100").is_err());
+
+    assert_eq!(cpu.register_a, 0x42);
+    assert_eq!(cpu.program_counter, 0x8000);
+    assert_eq!(cpu.status, 0x21);
+  }
+}
@@ -0,0 +1,185 @@
+use crate::cpu::AddressingMode;
+use crate::ops::Op;
+use crate::ops::OPS_MAP;
+use std::collections::BTreeSet;
+
+pub struct Instruction {
+  pub addr: u16,
+  pub bytes: Vec<u8>,
+  pub op: Option<&'static Op>,
+}
+
+impl Instruction {
+  // Destination of a branch, JMP absolute or JSR
+  pub fn target(&self) -> Option<u16> {
+    let op = self.op?;
+    match (op.code, &op.mode, self.bytes.len()) {
+      (0x4c, _, 3) | (0x20, _, 3) => Some(u16::from_le_bytes([self.bytes[1], self.bytes[2]])),
+      (_, AddressingMode::NoneAddressing, 2) => Some(
+        self.addr
+          .wrapping_add(2)
+          .wrapping_add(self.bytes[1] as i8 as u16),
+      ),
+      _ => None,
+    }
+  }
+}
+
+// Decodes the instruction at the start of data, unknown or truncated opcodes become a single byte
+pub fn decode(data: &[u8], addr: u16) -> Instruction {
+  match OPS_MAP.get(&data[0]) {
+    Some(op) if data.len() >= op.len as usize => Instruction {
+      addr,
+      bytes: data[..op.len as usize].to_vec(),
+      op: Some(op),
+    },
+    _ => Instruction {
+      addr,
+      bytes: vec![data[0]],
+      op: None,
+    },
+  }
+}
+
+pub fn format_operand(ins: &Instruction, label: &dyn Fn(u16) -> String) -> String {
+  let op = match ins.op {
+    Some(op) => op,
+    None => return format!("${:02X}", ins.bytes[0]),
+  };
+
+  let byte = ins.bytes.get(1).copied().unwrap_or(0);
+  let word = u16::from_le_bytes([byte, ins.bytes.get(2).copied().unwrap_or(0)]);
+
+  match op.mode {
+    AddressingMode::Immediate => format!("#${:02X}", byte),
+    AddressingMode::ZeroPage => format!("${:02X}", byte),
+    AddressingMode::ZeroPage_X => format!("${:02X},X", byte),
+    AddressingMode::ZeroPage_Y => format!("${:02X},Y", byte),
+    AddressingMode::Absolute => format!("${:04X}", word),
+    AddressingMode::Absolute_X => format!("${:04X},X", word),
+    AddressingMode::Absolute_Y => format!("${:04X},Y", word),
+    AddressingMode::Indirect_X => format!("(${:02X},X)", byte),
+    AddressingMode::Indirect_Y => format!("(${:02X}),Y", byte),
+    AddressingMode::NoneAddressing => match (op.len, op.code) {
+      // accumulator addressing modes
+      (1, 0x0a) | (1, 0x2a) | (1, 0x4a) | (1, 0x6a) => String::from("A"),
+      (3, 0x6c) => format!("(${:04X})", word),
+      (1, _) => String::new(),
+      _ => label(ins.target().unwrap_or(word)),
+    },
+  }
+}
+
+pub fn format_instruction(ins: &Instruction, label: &dyn Fn(u16) -> String) -> String {
+  let bytes: Vec<String> = ins.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+  let mnemonic = ins.op.map_or(".db", |op| op.ins);
+  let line = format!(
+    "{:04X}  {:9} {:>4} {}",
+    ins.addr,
+    bytes.join(" "),
+    mnemonic,
+    format_operand(ins, label),
+  );
+  line.trim_end().to_string()
+}
+
+pub fn label_name(addr: u16) -> String {
+  format!("L_{:04X}", addr)
+}
+
+pub fn address(addr: u16) -> String {
+  format!("${:04X}", addr)
+}
+
+// Linear sweep of data loaded at origin, with labels on every branch, JMP and JSR target in range
+pub fn disassemble(data: &[u8], origin: u16) -> Vec<String> {
+  let mut instructions = vec![];
+  let mut offset = 0;
+  while offset < data.len() {
+    let ins = decode(&data[offset..], origin.wrapping_add(offset as u16));
+    offset += ins.bytes.len();
+    instructions.push(ins);
+  }
+
+  let end = origin as usize + data.len();
+  let in_range = |addr: u16| (origin as usize..end).contains(&(addr as usize));
+  let instruction_starts: BTreeSet<u16> = instructions.iter().map(|ins| ins.addr).collect();
+  let labels: BTreeSet<u16> = instructions
+    .iter()
+    .filter_map(|ins| ins.target())
+    .filter(|addr| in_range(*addr))
+    .collect();
+
+  let label = |addr: u16| {
+    if labels.contains(&addr) && instruction_starts.contains(&addr) {
+      label_name(addr)
+    } else {
+      address(addr)
+    }
+  };
+
+  let mut lines = vec![];
+  for ins in instructions.iter() {
+    if labels.contains(&ins.addr) {
+      lines.push(format!("{}:", label_name(ins.addr)));
+    }
+    lines.push(format_instruction(ins, &label));
+  }
+  lines
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_disassemble() {
+    /*
+      loop:
+        LDX #$00
+        INX
+        BNE loop
+        JSR $1234
+        *NOP $10
+        KIL
+        JMP ($0200)
+        LSR A
+    */
+    let program = [0xa2, 0x00, 0xe8, 0xd0, 0xfb, 0x20, 0x34, 0x12, 0x04, 0x10, 0x02, 0x6c, 0x00, 0x02, 0x4a];
+
+    assert_eq!(
+      disassemble(&program, 0xc000),
+      vec![
+        "L_C000:",
+        "C000  A2 00      LDX #$00",
+        "C002  E8         INX",
+        "C003  D0 FB      BNE L_C000",
+        "C005  20 34 12   JSR $1234",
+        "C008  04 10     *NOP $10",
+        "C00A  02        *KIL",
+        "C00B  6C 00 02   JMP ($0200)",
+        "C00E  4A         LSR A",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_truncated_instruction() {
+    assert_eq!(
+      disassemble(&[0xe8, 0x4c, 0x00], 0x8000),
+      vec!["8000  E8         INX", "8001  4C         .db $4C", "8002  00         BRK"]
+    );
+  }
+
+  #[test]
+  fn test_branch_target() {
+    let ins = decode(&[0x10, 0x02], 0x80fe);
+    assert_eq!(ins.target(), Some(0x8102));
+
+    let ins = decode(&[0x4c, 0x34, 0x12], 0x8000);
+    assert_eq!(ins.target(), Some(0x1234));
+
+    let ins = decode(&[0x6c, 0x34, 0x12], 0x8000);
+    assert_eq!(ins.target(), None);
+  }
+}
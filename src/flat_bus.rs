@@ -0,0 +1,222 @@
+use crate::cpu::CpuBus;
+use crate::cpu::Mem;
+
+// 64KB of RAM and a cycle counter, enough to run plain 6502 programs on the CPU core
+pub struct FlatBus {
+  memory: Vec<u8>,
+  cycles: usize,
+  irq: bool,
+}
+
+impl FlatBus {
+  // The reset vector starts out pointing at $0600, where CPU::load puts programs
+  pub fn new() -> Self {
+    let mut memory = vec![0; 0x10000];
+    memory[0xfffc..0xfffe].copy_from_slice(&[0x00, 0x06]);
+    FlatBus {
+      memory,
+      cycles: 0,
+      irq: false,
+    }
+  }
+
+  // Copies a program or a memory image in, starting at `addr`
+  pub fn load(&mut self, addr: u16, data: &[u8]) {
+    let start = addr as usize;
+    self.memory[start..start + data.len()].copy_from_slice(data);
+  }
+
+  // Holds the IRQ line asserted until it's released again
+  pub fn set_irq(&mut self, irq: bool) {
+    self.irq = irq;
+  }
+}
+
+impl Default for FlatBus {
+  fn default() -> Self {
+    FlatBus::new()
+  }
+}
+
+impl Mem for FlatBus {
+  fn mem_read(&mut self, addr: u16) -> u8 {
+    self.memory[addr as usize]
+  }
+
+  fn mem_peek(&self, addr: u16) -> u8 {
+    self.memory[addr as usize]
+  }
+
+  fn mem_write(&mut self, addr: u16, data: u8) {
+    self.memory[addr as usize] = data;
+  }
+}
+
+impl CpuBus for FlatBus {
+  fn tick(&mut self, cycles: usize) {
+    self.cycles += cycles;
+  }
+
+  fn cycles(&self) -> usize {
+    self.cycles
+  }
+
+  fn irq_pending(&mut self) -> bool {
+    self.irq
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::cpu::Variant;
+  use crate::cpu::CPU;
+
+  fn flat_cpu(program: &[u8]) -> CPU<'static, FlatBus> {
+    let mut bus = FlatBus::new();
+    bus.load(0x0200, program);
+    bus.load(0xfffc, &[0x00, 0x02]);
+    let mut cpu = CPU::with_bus(bus);
+    cpu.power_on();
+    cpu
+  }
+
+  #[test]
+  fn test_run_program() {
+    // Adds 3 five times and stores the sum at $8000, which is plain RAM here
+    let mut cpu = flat_cpu(&[
+      0xa2, 0x05, 0xa9, 0x00, 0x18, 0x69, 0x03, 0xca, 0xd0, 0xfb, 0x8d, 0x00, 0x80, 0x00,
+    ]);
+    assert_eq!(cpu.program_counter, 0x0200);
+    cpu.run();
+
+    assert_eq!(cpu.mem_peek(0x8000), 15);
+    assert_eq!(cpu.program_counter, 0x020e);
+    assert_eq!(cpu.bus.cycles(), 7 + 6 + 5 * 4 + 4 * 3 + 2 + 4 + 1);
+  }
+
+  #[test]
+  fn test_load_and_run() {
+    // LDA #$42, STA $10
+    let mut cpu = CPU::with_bus(FlatBus::new());
+    cpu.load_and_run(vec![0xa9, 0x42, 0x85, 0x10, 0x00]);
+    assert_eq!(cpu.register_a, 0x42);
+    assert_eq!(cpu.mem_peek(0x10), 0x42);
+    assert_eq!(cpu.program_counter, 0x0605);
+  }
+
+  // Steps until the program traps in a jump or branch to itself
+  fn run_to_trap(cpu: &mut CPU<FlatBus>) -> u16 {
+    loop {
+      let pc = cpu.program_counter;
+      cpu.step_instruction();
+      if cpu.program_counter == pc {
+        return pc;
+      }
+    }
+  }
+
+  #[test]
+  fn test_decimal_program() {
+    // Built from decimal_test.a65, it leaves 0 at $0B when every ADC and SBC matched
+    let mut cpu = flat_cpu(include_bytes!("../decimal_test.bin"));
+    cpu.set_variant(Variant::Nmos6502);
+    assert_eq!(run_to_trap(&mut cpu), 0x024b);
+    assert_eq!(cpu.mem_peek(0x0b), 0);
+
+    let mut cpu = flat_cpu(include_bytes!("../decimal_test.bin"));
+    assert_eq!(run_to_trap(&mut cpu), 0x024b);
+    assert_eq!(cpu.mem_peek(0x0b), 1);
+  }
+
+  // Documented cycles of every opcode without penalties, 0 marks the KIL opcodes
+  #[rustfmt::skip]
+  const CYCLES: [usize; 256] = [
+    7, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 6, 0, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 5, 0, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+  ];
+
+  // Reads through abs,X, abs,Y and (ind),Y take a cycle more when the index crosses a page
+  fn page_cross_penalty(opcode: u8) -> bool {
+    match opcode & 0x1f {
+      0x11 | 0x19 | 0x1c | 0x1d => opcode != 0x91 && opcode != 0x99 && opcode != 0x9c && opcode != 0x9d,
+      0x13 | 0x1b | 0x1e | 0x1f => opcode & 0xe0 == 0xa0,
+      _ => false,
+    }
+  }
+
+  fn is_branch(opcode: u8) -> bool {
+    opcode & 0x1f == 0x10
+  }
+
+  // Runs one instruction from `addr` on the NMOS variant, where BRK is a 7 cycle interrupt
+  fn opcode_cycles(addr: u16, program: &[u8], index: u8, status: u8) -> (usize, u16) {
+    let mut cpu = flat_cpu(&[]);
+    cpu.bus.load(addr, program);
+    // Pointer for (ind),Y and (ind,X) at $10 and at $ff, which wraps to $00
+    cpu.bus.load(0x0000, &[0x03]);
+    cpu.bus.load(0x0010, &[0x10, 0x03]);
+    cpu.bus.load(0x00ff, &[0xff]);
+    cpu.set_variant(Variant::Nmos6502);
+    cpu.program_counter = addr;
+    cpu.register_x = index;
+    cpu.register_y = index;
+    cpu.status = status;
+
+    let cycles = cpu.bus.cycles();
+    cpu.step_instruction();
+    (cpu.bus.cycles() - cycles, cpu.program_counter)
+  }
+
+  #[test]
+  fn test_opcode_cycles() {
+    for opcode in 0..=0xffu8 {
+      let base = CYCLES[opcode as usize];
+      if base == 0 || is_branch(opcode) {
+        continue;
+      }
+
+      let (cycles, _) = opcode_cycles(0x0200, &[opcode, 0x10, 0x03], 0x01, 0x24);
+      assert_eq!(cycles, base, "opcode {:02x}", opcode);
+
+      // $03ff + $ff crosses into page $04 for both the absolute and the indirect operand
+      let penalty = page_cross_penalty(opcode) as usize;
+      let (cycles, _) = opcode_cycles(0x0200, &[opcode, 0xff, 0x03], 0xff, 0x24);
+      assert_eq!(cycles, base + penalty, "opcode {:02x} crossing a page", opcode);
+    }
+  }
+
+  #[test]
+  fn test_branch_cycles() {
+    for opcode in (0x10..=0xf0u8).step_by(0x20) {
+      // Each branch is taken with either every flag clear or N, V, Z and C set
+      for status in [0x24, 0xe7] {
+        let (cycles, pc) = opcode_cycles(0x0200, &[opcode, 0x10], 0, status);
+        if pc == 0x0202 {
+          assert_eq!(cycles, 2, "opcode {:02x} not taken", opcode);
+          continue;
+        }
+        assert_eq!(pc, 0x0212);
+        assert_eq!(cycles, 3, "opcode {:02x} taken", opcode);
+
+        let (cycles, pc) = opcode_cycles(0x02f0, &[opcode, 0x10], 0, status);
+        assert_eq!(pc, 0x0302);
+        assert_eq!(cycles, 4, "opcode {:02x} taken across a page", opcode);
+      }
+    }
+  }
+}
@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+  Quit,
+  Pause,
+  FrameAdvance,
+  FastForward,
+  Rewind,
+  Reset,
+  PowerCycle,
+  SaveState(usize),
+  LoadState(usize),
+}
+
+impl Command {
+  pub fn from_name(name: &str) -> Result<Command, String> {
+    let slot = |prefix: &str| name.strip_prefix(prefix).and_then(|slot| slot.parse::<usize>().ok());
+    match name {
+      "quit" => Ok(Command::Quit),
+      "pause" => Ok(Command::Pause),
+      "frame_advance" => Ok(Command::FrameAdvance),
+      "fast_forward" => Ok(Command::FastForward),
+      "rewind" => Ok(Command::Rewind),
+      "reset" => Ok(Command::Reset),
+      "power" => Ok(Command::PowerCycle),
+      _ => {
+        if let Some(slot) = slot("save") {
+          Ok(Command::SaveState(slot))
+        } else if let Some(slot) = slot("load") {
+          Ok(Command::LoadState(slot))
+        } else {
+          Err(format!("Unknown command: {}", name))
+        }
+      },
+    }
+  }
+}
+
+// Frontend state driven by hotkeys. Commands that need the CPU are queued, since the gameloop
+// callback only sees the PPU
+pub struct Frontend {
+  paused: bool,
+  advance: bool,
+  fast_forward: bool,
+  rewinding: bool,
+  quit: bool,
+  pending: VecDeque<Command>,
+}
+
+impl Frontend {
+  pub fn new() -> Self {
+    Frontend {
+      paused: false,
+      advance: false,
+      fast_forward: false,
+      rewinding: false,
+      quit: false,
+      pending: VecDeque::new(),
+    }
+  }
+
+  pub fn handle(&mut self, command: Command, pressed: bool) {
+    match command {
+      Command::FastForward => self.fast_forward = pressed,
+      Command::Rewind => self.rewinding = pressed,
+      _ if !pressed => {},
+      Command::Quit => self.quit = true,
+      Command::Pause => self.paused = !self.paused,
+      Command::FrameAdvance => {
+        self.paused = true;
+        self.advance = true;
+      },
+      Command::Reset | Command::PowerCycle | Command::SaveState(_) | Command::LoadState(_) => {
+        self.pending.push_back(command);
+      },
+    }
+  }
+
+  // Whether the next frame has to wait, frame advance lets exactly one frame through
+  pub fn hold_frame(&mut self) -> bool {
+    if self.advance {
+      self.advance = false;
+      return false;
+    }
+    self.paused && !self.quit
+  }
+
+  pub fn fast_forward(&self) -> bool {
+    self.fast_forward
+  }
+
+  pub fn paused(&self) -> bool {
+    self.paused
+  }
+
+  pub fn rewinding(&self) -> bool {
+    self.rewinding
+  }
+
+  pub fn quit(&self) -> bool {
+    self.quit
+  }
+
+  pub fn has_pending(&self) -> bool {
+    !self.pending.is_empty()
+  }
+
+  pub fn take_pending(&mut self) -> Option<Command> {
+    self.pending.pop_front()
+  }
+}
+
+impl Default for Frontend {
+  fn default() -> Self {
+    Frontend::new()
+  }
+}
+
+// Holds presented frames to the console's frame rate, PAL games can't rely on a 60Hz vsync
+pub struct FramePacer {
+  frame_time: Duration,
+  next: Instant,
+}
+
+impl FramePacer {
+  pub fn new(frame_rate: f64) -> Self {
+    FramePacer {
+      frame_time: Duration::from_secs_f64(1.0 / frame_rate),
+      next: Instant::now(),
+    }
+  }
+
+  // Sleeps until the next frame is due. After falling more than a frame behind, e.g. while
+  // paused, it starts over from now rather than rushing to catch up
+  pub fn wait(&mut self) {
+    let now = Instant::now();
+    if self.next > now {
+      std::thread::sleep(self.next - now);
+    } else if now - self.next > self.frame_time {
+      self.next = now;
+    }
+    self.next += self.frame_time;
+  }
+
+  // Whether a frame is due, without waiting. Fast-forward runs unthrottled and uses it to
+  // present at the normal rate
+  pub fn due(&mut self) -> bool {
+    let now = Instant::now();
+    if now < self.next {
+      return false;
+    }
+    self.next = now + self.frame_time;
+    true
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_command_names() {
+    assert_eq!(Command::from_name("power"), Ok(Command::PowerCycle));
+    assert_eq!(Command::from_name("save3"), Ok(Command::SaveState(3)));
+    assert_eq!(Command::from_name("load12"), Ok(Command::LoadState(12)));
+    assert_eq!(Command::from_name("loadx"), Err("Unknown command: loadx".to_string()));
+  }
+
+  #[test]
+  fn test_pause_and_frame_advance() {
+    let mut frontend = Frontend::new();
+    assert!(!frontend.hold_frame());
+
+    frontend.handle(Command::Pause, true);
+    frontend.handle(Command::Pause, false);
+    assert!(frontend.hold_frame());
+
+    frontend.handle(Command::FrameAdvance, true);
+    assert!(!frontend.hold_frame());
+    assert!(frontend.hold_frame());
+
+    frontend.handle(Command::Pause, true);
+    assert!(!frontend.paused());
+    assert!(!frontend.hold_frame());
+  }
+
+  #[test]
+  fn test_fast_forward_and_pending() {
+    let mut frontend = Frontend::new();
+    frontend.handle(Command::FastForward, true);
+    assert!(frontend.fast_forward());
+    frontend.handle(Command::FastForward, false);
+    assert!(!frontend.fast_forward());
+
+    frontend.handle(Command::Reset, true);
+    frontend.handle(Command::Reset, false);
+    frontend.handle(Command::SaveState(2), true);
+    assert!(frontend.has_pending());
+    assert_eq!(frontend.take_pending(), Some(Command::Reset));
+    assert_eq!(frontend.take_pending(), Some(Command::SaveState(2)));
+    assert_eq!(frontend.take_pending(), None);
+    assert!(!frontend.has_pending());
+  }
+
+  #[test]
+  fn test_pacer_due() {
+    let mut pacer = FramePacer::new(1.0);
+    assert!(pacer.due());
+    assert!(!pacer.due());
+  }
+}
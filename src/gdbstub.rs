@@ -0,0 +1,478 @@
+use crate::cpu::Mem;
+use crate::cpu::CPU;
+use crate::debugger::memory_access;
+use crate::ops::OPS_MAP;
+use std::collections::HashSet;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+
+// Instructions executed between checks for a Ctrl-C (0x03) from the client
+const INTERRUPT_POLL_INTERVAL: usize = 0x1000;
+const PACKET_SIZE: usize = 0x1000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nes.cpu">
+    <reg name="a" bitsize="8" regnum="0" type="uint8"/>
+    <reg name="x" bitsize="8" regnum="1" type="uint8"/>
+    <reg name="y" bitsize="8" regnum="2" type="uint8"/>
+    <reg name="p" bitsize="8" regnum="3" type="uint8"/>
+    <reg name="sp" bitsize="8" regnum="4" type="uint8"/>
+    <reg name="pc" bitsize="16" regnum="5" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+#[derive(Debug, PartialEq)]
+enum State {
+  Stopped,
+  Running,
+  Stepping,
+  Detached,
+}
+
+// Everything about the debug session except the connection itself
+pub struct Target {
+  breakpoints: HashSet<u16>,
+  read_watchpoints: HashSet<u16>,
+  write_watchpoints: HashSet<u16>,
+  state: State,
+}
+
+pub struct GdbStub {
+  stream: TcpStream,
+  target: Target,
+  instructions: usize,
+}
+
+pub fn checksum(data: &str) -> u8 {
+  data.bytes().fold(0, |sum, b| sum.wrapping_add(b))
+}
+
+pub fn frame(data: &str) -> String {
+  format!("${}#{:02x}", data, checksum(data))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(data: &str) -> Option<Vec<u8>> {
+  if !data.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..data.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+fn parse_hex(data: &str) -> Option<u16> {
+  u16::from_str_radix(data, 16).ok()
+}
+
+// Parses "addr,len" as used by m, M and Z packets
+fn parse_range(data: &str) -> Option<(u16, usize)> {
+  let (addr, len) = data.split_once(',')?;
+  Some((parse_hex(addr)?, usize::from_str_radix(len, 16).ok()?))
+}
+
+// Only RAM takes a write without side effects, the registers above it would poke the
+// PPU, start OAM DMA or strobe the controllers, and PRG ROM panics
+fn writable(addr: u16) -> bool {
+  addr < 0x2000
+}
+
+impl Default for Target {
+  fn default() -> Self {
+    Target::new()
+  }
+}
+
+impl Target {
+  pub fn new() -> Self {
+    Target {
+      breakpoints: HashSet::new(),
+      read_watchpoints: HashSet::new(),
+      write_watchpoints: HashSet::new(),
+      state: State::Stopped,
+    }
+  }
+
+  fn registers(cpu: &CPU) -> [u8; 7] {
+    let pc = cpu.program_counter.to_le_bytes();
+    [cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer, pc[0], pc[1]]
+  }
+
+  fn set_register(cpu: &mut CPU, reg: usize, value: &[u8]) -> bool {
+    match (reg, value) {
+      (0, [v]) => cpu.register_a = *v,
+      (1, [v]) => cpu.register_x = *v,
+      (2, [v]) => cpu.register_y = *v,
+      (3, [v]) => cpu.status = *v,
+      (4, [v]) => cpu.stack_pointer = *v,
+      (5, [lo, hi]) => cpu.program_counter = u16::from_le_bytes([*lo, *hi]),
+      _ => return false,
+    }
+    true
+  }
+
+  // Stop reply if the instruction at pc hits a breakpoint or watchpoint
+  pub fn check_stop(&self, cpu: &CPU) -> Option<String> {
+    if self.state == State::Stepping || self.breakpoints.contains(&cpu.program_counter) {
+      return Some(String::from("S05"));
+    }
+
+    let op = OPS_MAP.get(&cpu.mem_peek(cpu.program_counter))?;
+    match memory_access(cpu, op) {
+      Some((addr, true, true)) if self.read_watchpoints.contains(&addr) && self.write_watchpoints.contains(&addr) => {
+        Some(format!("T05awatch:{:04x};", addr))
+      }
+      Some((addr, true, _)) if self.read_watchpoints.contains(&addr) => Some(format!("T05rwatch:{:04x};", addr)),
+      Some((addr, _, true)) if self.write_watchpoints.contains(&addr) => Some(format!("T05watch:{:04x};", addr)),
+      _ => None,
+    }
+  }
+
+  fn set_breakpoint(&mut self, packet: &str, insert: bool) -> Option<String> {
+    let (kind, range) = packet.split_once(',')?;
+    let (addr, _) = parse_range(range)?;
+
+    let sets: Vec<&mut HashSet<u16>> = match kind {
+      "0" | "1" => vec![&mut self.breakpoints],
+      "2" => vec![&mut self.write_watchpoints],
+      "3" => vec![&mut self.read_watchpoints],
+      "4" => vec![&mut self.read_watchpoints, &mut self.write_watchpoints],
+      _ => return Some(String::new()),
+    };
+    for set in sets {
+      if insert {
+        set.insert(addr);
+      } else {
+        set.remove(&addr);
+      }
+    }
+    Some(String::from("OK"))
+  }
+
+  fn resume(&mut self, cpu: &mut CPU, addr: &str, state: State) -> Option<String> {
+    if let Some(addr) = parse_hex(addr) {
+      cpu.program_counter = addr;
+    }
+    self.state = state;
+    None
+  }
+
+  // Returns the reply to send, or None when the packet resumes execution
+  pub fn handle(&mut self, cpu: &mut CPU, packet: &str) -> Option<String> {
+    let (command, args) = packet.split_at(packet.len().min(1));
+    let error = String::from("E01");
+
+    match command {
+      "?" => Some(String::from("S05")),
+      "g" => Some(encode_hex(&Target::registers(cpu))),
+      "G" => {
+        let values = match decode_hex(args) {
+          Some(values) if values.len() == 7 => values,
+          _ => return Some(error),
+        };
+        cpu.register_a = values[0];
+        cpu.register_x = values[1];
+        cpu.register_y = values[2];
+        cpu.status = values[3];
+        cpu.stack_pointer = values[4];
+        cpu.program_counter = u16::from_le_bytes([values[5], values[6]]);
+        Some(String::from("OK"))
+      }
+      "p" => {
+        let registers = Target::registers(cpu);
+        match usize::from_str_radix(args, 16) {
+          Ok(reg @ 0..=4) => Some(encode_hex(&registers[reg..reg + 1])),
+          Ok(5) => Some(encode_hex(&registers[5..7])),
+          _ => Some(error),
+        }
+      }
+      "P" => {
+        let parsed = args
+          .split_once('=')
+          .and_then(|(reg, value)| Some((usize::from_str_radix(reg, 16).ok()?, decode_hex(value)?)));
+        match parsed {
+          Some((reg, value)) if Target::set_register(cpu, reg, &value) => Some(String::from("OK")),
+          _ => Some(error),
+        }
+      }
+      "m" => {
+        let (addr, len) = match parse_range(args) {
+          Some(range) => range,
+          None => return Some(error),
+        };
+        let bytes: Vec<u8> = (0..len.min(PACKET_SIZE / 2))
+          .map(|i| cpu.mem_peek(addr.wrapping_add(i as u16)))
+          .collect();
+        Some(encode_hex(&bytes))
+      }
+      "M" => {
+        let parsed = args
+          .split_once(':')
+          .and_then(|(range, data)| Some((parse_range(range)?, decode_hex(data)?)));
+        let (addr, bytes) = match parsed {
+          Some(((addr, len), bytes)) if bytes.len() == len => (addr, bytes),
+          _ => return Some(error),
+        };
+        if !(0..bytes.len()).all(|i| writable(addr.wrapping_add(i as u16))) {
+          return Some(String::from("E0e"));
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+          cpu.mem_write(addr.wrapping_add(i as u16), *byte);
+        }
+        Some(String::from("OK"))
+      }
+      "Z" => self.set_breakpoint(args, true).or(Some(error)),
+      "z" => self.set_breakpoint(args, false).or(Some(error)),
+      "c" => self.resume(cpu, args, State::Running),
+      "s" => self.resume(cpu, args, State::Stepping),
+      "D" => {
+        self.state = State::Detached;
+        Some(String::from("OK"))
+      }
+      "k" => std::process::exit(0),
+      "H" => Some(String::from("OK")),
+      "q" => {
+        if args.starts_with("Supported") {
+          Some(format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE))
+        } else if args == "Attached" {
+          Some(String::from("1"))
+        } else if args == "C" {
+          Some(String::from("QC1"))
+        } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+          let (offset, len) = match parse_range(range) {
+            Some((offset, len)) => (offset as usize, len),
+            None => return Some(error),
+          };
+          let chunk = TARGET_XML.get(offset..TARGET_XML.len().min(offset + len)).unwrap_or("");
+          let more = offset + chunk.len() < TARGET_XML.len();
+          Some(format!("{}{}", if more { "m" } else { "l" }, chunk))
+        } else {
+          Some(String::new())
+        }
+      }
+      _ => Some(String::new()),
+    }
+  }
+}
+
+impl GdbStub {
+  // Blocks until a client connects to localhost:port
+  pub fn listen(port: u16) -> std::io::Result<GdbStub> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for gdb on 127.0.0.1:{}", port);
+    let (stream, addr) = listener.accept()?;
+    println!("gdb connected from {}", addr);
+    stream.set_nodelay(true)?;
+
+    Ok(GdbStub {
+      stream,
+      target: Target::new(),
+      instructions: 0,
+    })
+  }
+
+  // Callback for CPU::run_with_callback
+  pub fn on_instruction(&mut self, cpu: &mut CPU) {
+    let stop = match self.target.state {
+      State::Detached => return,
+      State::Stopped => None,
+      State::Running | State::Stepping => {
+        self.instructions = self.instructions.wrapping_add(1);
+        let stop = self.target.check_stop(cpu);
+        if stop.is_none() && self.instructions.is_multiple_of(INTERRUPT_POLL_INTERVAL) && self.poll_interrupt() {
+          Some(String::from("S02"))
+        } else {
+          stop
+        }
+      }
+    };
+
+    if let Some(reply) = stop {
+      self.target.state = State::Stopped;
+      self.send(&reply);
+    }
+
+    if self.target.state == State::Stopped {
+      self.serve(cpu);
+    }
+  }
+
+  fn serve(&mut self, cpu: &mut CPU) {
+    while self.target.state == State::Stopped {
+      let packet = match self.read_packet() {
+        Ok(Some(packet)) => packet,
+        Ok(None) => continue,
+        Err(e) => {
+          println!("gdb disconnected: {}", e);
+          self.target.state = State::Detached;
+          return;
+        }
+      };
+
+      if let Some(reply) = self.target.handle(cpu, &packet) {
+        self.send(&reply);
+      }
+    }
+  }
+
+  fn poll_interrupt(&mut self) -> bool {
+    let mut byte = [0u8; 1];
+    if self.stream.set_nonblocking(true).is_err() {
+      return false;
+    }
+    let interrupted = matches!(self.stream.read(&mut byte), Ok(1) if byte[0] == 0x03);
+    let _ = self.stream.set_nonblocking(false);
+    interrupted
+  }
+
+  fn read_byte(&mut self) -> std::io::Result<u8> {
+    let mut byte = [0u8; 1];
+    self.stream.read_exact(&mut byte)?;
+    Ok(byte[0])
+  }
+
+  // Returns None for acks and out-of-band bytes
+  fn read_packet(&mut self) -> std::io::Result<Option<String>> {
+    if self.read_byte()? != b'$' {
+      return Ok(None);
+    }
+
+    let mut data = Vec::new();
+    loop {
+      match self.read_byte()? {
+        b'#' => break,
+        byte => data.push(byte),
+      }
+    }
+    let sum = [self.read_byte()?, self.read_byte()?];
+
+    let data = String::from_utf8_lossy(&data).to_string();
+    let valid = std::str::from_utf8(&sum)
+      .ok()
+      .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+      == Some(checksum(&data));
+
+    self.stream.write_all(if valid { b"+" } else { b"-" })?;
+    Ok(if valid { Some(data) } else { None })
+  }
+
+  fn send(&mut self, data: &str) {
+    if let Err(e) = self.stream.write_all(frame(data).as_bytes()) {
+      println!("gdb disconnected: {}", e);
+      self.target.state = State::Detached;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::rom::test::test_rom;
+
+  fn test_cpu() -> CPU<'static> {
+    let mut cpu = CPU::new(test_rom());
+    cpu.power_on();
+    cpu
+  }
+
+  #[test]
+  fn test_frame() {
+    assert_eq!(frame("OK"), "$OK#9a");
+    assert_eq!(frame(""), "$#00");
+  }
+
+  #[test]
+  fn test_registers() {
+    let mut cpu = test_cpu();
+    let mut target = Target::new();
+    cpu.register_a = 0x12;
+    cpu.program_counter = 0xc000;
+
+    assert_eq!(target.handle(&mut cpu, "g"), Some(String::from("12000024fd00c0")));
+    assert_eq!(target.handle(&mut cpu, "p5"), Some(String::from("00c0")));
+
+    assert_eq!(target.handle(&mut cpu, "G0102032404fe80"), Some(String::from("OK")));
+    assert_eq!(cpu.register_y, 0x03);
+    assert_eq!(cpu.stack_pointer, 0x04);
+    assert_eq!(cpu.program_counter, 0x80fe);
+
+    assert_eq!(target.handle(&mut cpu, "P1=7f"), Some(String::from("OK")));
+    assert_eq!(cpu.register_x, 0x7f);
+    assert_eq!(target.handle(&mut cpu, "P5=7f"), Some(String::from("E01")));
+  }
+
+  #[test]
+  fn test_memory() {
+    let mut cpu = test_cpu();
+    let mut target = Target::new();
+
+    assert_eq!(target.handle(&mut cpu, "M10,3:aabbcc"), Some(String::from("OK")));
+    assert_eq!(target.handle(&mut cpu, "m0f,5"), Some(String::from("00aabbcc00")));
+    // RAM mirror
+    assert_eq!(target.handle(&mut cpu, "m0810,1"), Some(String::from("aa")));
+    assert_eq!(target.handle(&mut cpu, "M8000,1:00"), Some(String::from("E0e")));
+  }
+
+  #[test]
+  fn test_memory_write_io() {
+    let mut cpu = test_cpu();
+    let mut target = Target::new();
+    let state = cpu.save_state();
+
+    // PPU address, OAM DMA, and a range running from RAM into the PPU registers
+    assert_eq!(target.handle(&mut cpu, "M2006,1:3f"), Some(String::from("E0e")));
+    assert_eq!(target.handle(&mut cpu, "M4014,1:02"), Some(String::from("E0e")));
+    assert_eq!(target.handle(&mut cpu, "M1fff,2:0102"), Some(String::from("E0e")));
+    assert_eq!(cpu.save_state(), state);
+  }
+
+  #[test]
+  fn test_breakpoints_and_step() {
+    // INX, STA $10, INX, BRK
+    let mut cpu = test_cpu();
+    for (i, byte) in [0xe8, 0x85, 0x10, 0xe8, 0x00].iter().enumerate() {
+      cpu.mem_write(0x600 + i as u16, *byte);
+    }
+    cpu.program_counter = 0x600;
+
+    let mut target = Target::new();
+    assert_eq!(target.handle(&mut cpu, "Z0,604,1"), Some(String::from("OK")));
+    assert_eq!(target.handle(&mut cpu, "Z2,10,1"), Some(String::from("OK")));
+    assert_eq!(target.handle(&mut cpu, "c"), None);
+
+    let mut stops = vec![];
+    cpu.run_with_callback(|cpu| {
+      if let Some(reply) = target.check_stop(cpu) {
+        stops.push(reply);
+        let command = if stops.len() == 1 { "s" } else { "c" };
+        target.handle(cpu, command);
+      }
+    });
+
+    assert_eq!(stops, vec!["T05watch:0010;", "S05", "S05"]);
+
+    assert_eq!(target.handle(&mut cpu, "z0,604,1"), Some(String::from("OK")));
+    assert_eq!(target.handle(&mut cpu, "Z9,603,1"), Some(String::new()));
+  }
+
+  #[test]
+  fn test_target_xml() {
+    let mut cpu = test_cpu();
+    let mut target = Target::new();
+
+    let reply = target.handle(&mut cpu, "qXfer:features:read:target.xml:0,10").unwrap();
+    assert_eq!(reply, "m<?xml version=\"1");
+    let reply = target.handle(&mut cpu, "qXfer:features:read:target.xml:10,ffff").unwrap();
+    assert!(reply.starts_with('l'));
+    assert!(reply.ends_with("</target>\n"));
+  }
+}
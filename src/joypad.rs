@@ -1,4 +1,9 @@
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
 bitflags! {
+  #[derive(Default)]
   pub struct JoypadButton: u8 {
     const RIGHT     = 0b10000000;
     const LEFT      = 0b01000000;
@@ -34,17 +39,29 @@ impl Joypad {
   }
 
   pub fn read(&mut self) -> u8 {
+    let response = self.peek();
+
+    if !self.strobe && self.button_index <= 7 {
+      self.button_index += 1;
+    }
+
+    response
+  }
+
+  pub fn peek(&self) -> u8 {
     if self.button_index > 7 {
       return 1;
     }
 
-    let response = (self.button_status.bits >> self.button_index) & 1;
+    (self.button_status.bits >> self.button_index) & 1
+  }
 
-    if !self.strobe {
-      self.button_index += 1;
-    }
+  pub fn buttons(&self) -> JoypadButton {
+    self.button_status
+  }
 
-    response
+  pub fn set_buttons(&mut self, buttons: JoypadButton) {
+    self.button_status = buttons;
   }
 
   pub fn set_button_pressed(&mut self, key: JoypadButton, pressed: bool) {
@@ -55,3 +72,18 @@ impl Joypad {
     }
   }
 }
+
+impl Snapshot for Joypad {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_bool(self.strobe);
+    w.write_u8(self.button_index);
+    w.write_u8(self.button_status.bits);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    self.strobe = r.read_bool()?;
+    self.button_index = r.read_u8()?;
+    self.button_status = JoypadButton::from_bits_truncate(r.read_u8()?);
+    Ok(())
+  }
+}
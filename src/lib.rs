@@ -0,0 +1,28 @@
+pub mod cpu;
+pub mod ops;
+pub mod bus;
+pub mod flat_bus;
+pub mod rom;
+pub mod region;
+pub mod trace;
+pub mod ppu;
+pub mod render;
+pub mod joypad;
+pub mod controller;
+pub mod debugger;
+pub mod gdbstub;
+pub mod disasm;
+pub mod savestate;
+pub mod rewind;
+pub mod movie;
+pub mod bindings;
+pub mod frontend;
+pub mod nes;
+
+pub use nes::Nes;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
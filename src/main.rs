@@ -1,30 +1,246 @@
-pub mod cpu;
-pub mod ops;
-pub mod bus;
-pub mod rom;
-pub mod trace;
-pub mod ppu;
-pub mod render;
-pub mod joypad;
-
-use cpu::CPU;
-use rom::Rom;
+use nes_emulator::bindings;
+use nes_emulator::disasm;
+use nes_emulator::movie;
+use nes_emulator::render;
+use nes_emulator::rewind;
+use nes_emulator::savestate;
+use nes_emulator::cpu::CPU;
+use nes_emulator::bus::RamPattern;
+use nes_emulator::rom::Rom;
+use nes_emulator::rom::PRG_ROM_PAGE_SIZE;
+use nes_emulator::region::Region;
+use sdl2::controller::Button;
+use sdl2::controller::GameController;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::PixelFormatEnum;
-use ppu::PPU;
-use render::frame::Frame;
-use joypad::Joypad;
-use joypad::JoypadButton;
+use sdl2::EventPump;
+use sdl2::GameControllerSubsystem;
+use nes_emulator::ppu::PPU;
+use nes_emulator::render::frame::Frame;
+use nes_emulator::controller::Controllers;
+use nes_emulator::controller::DeviceKind;
+use nes_emulator::controller::InputState;
+use nes_emulator::debugger::Debugger;
+use nes_emulator::gdbstub::GdbStub;
+use nes_emulator::rewind::Rewind;
+use nes_emulator::frontend::Command;
+use nes_emulator::frontend::Frontend;
+use nes_emulator::frontend::FramePacer;
+use nes_emulator::movie::Movie;
+use nes_emulator::bindings::Action;
+use nes_emulator::bindings::Input;
+use nes_emulator::bindings::PlayerInput;
+use nes_emulator::bindings::Source;
 use std::collections::HashMap;
+use std::time::Duration;
+use std::cell::RefCell;
+use std::rc::Rc;
 
-#[macro_use]
-extern crate lazy_static;
+// How often events are polled while paused
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(16);
 
-#[macro_use]
-extern crate bitflags;
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+  let i = args.iter().position(|arg| arg == name)?;
+  args.get(i + 1).map(|value| value.as_str())
+}
+
+fn read_movie(path: &str) -> Result<Movie, String> {
+  let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+  if path.ends_with(".fm2") {
+    Movie::from_fm2(&text)
+  } else {
+    Movie::from_text(&text)
+  }
+}
+
+fn write_movie(movie: &Movie, path: &str, rom_path: &str) {
+  let text = if path.ends_with(".fm2") { movie.to_fm2(rom_path) } else { movie.to_text() };
+  match std::fs::write(path, text) {
+    Ok(_) => println!("Saved {} frame movie to {}", movie.len(), path),
+    Err(e) => println!("Failed to save movie to {}: {}", path, e),
+  }
+}
+
+// Carries out the queued frontend commands that need the CPU
+fn apply_command(cpu: &mut CPU, command: Command, rom_path: &str) {
+  match command {
+    Command::SaveState(slot) => {
+      let path = format!("{}.ss{}", rom_path, slot);
+      match std::fs::write(&path, cpu.save_state()) {
+        Ok(_) => println!("Saved state to {}", path),
+        Err(e) => println!("Failed to save state to {}: {}", path, e),
+      }
+    },
+    Command::LoadState(slot) => {
+      let path = format!("{}.ss{}", rom_path, slot);
+      let result = std::fs::read(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| cpu.load_state(&data));
+      match result {
+        Ok(_) => println!("Loaded state from {}", path),
+        Err(e) => println!("Failed to load state from {}: {}", path, e),
+      }
+    },
+    Command::Reset => cpu.reset(),
+    Command::PowerCycle => cpu.power_on(),
+    _ => {},
+  }
+}
+
+type KeyBindings = HashMap<Keycode, Vec<(usize, Action)>>;
+type PadBindings = HashMap<(usize, Button), Vec<Action>>;
+
+fn load_bindings(path: Option<&str>) -> Result<(KeyBindings, PadBindings), String> {
+  let bindings = match path {
+    Some(path) => bindings::parse(&std::fs::read_to_string(path).map_err(|e| e.to_string())?)?,
+    None => bindings::default_bindings(),
+  };
+
+  let mut keys: KeyBindings = HashMap::new();
+  let mut pads: PadBindings = HashMap::new();
+  for binding in bindings {
+    match binding.input {
+      Input::Key(name) => {
+        let keycode = Keycode::from_name(&name).ok_or_else(|| format!("Unknown key: {}", name))?;
+        keys.entry(keycode).or_default().push((binding.player, binding.action));
+      },
+      Input::Pad(name) => {
+        let button = Button::from_string(&name).ok_or_else(|| format!("Unknown pad button: {}", name))?;
+        pads.entry((binding.player, button)).or_default().push(binding.action);
+      },
+    }
+  }
+  Ok((keys, pads))
+}
+
+fn gamepad_player(gamepads: &[Option<GameController>], which: u32) -> Option<usize> {
+  gamepads.iter().position(|slot| slot.as_ref().is_some_and(|gamepad| gamepad.instance_id() == which))
+}
+
+// Power Pad side B, buttons 1-12 in rows of four
+const POWER_PAD_KEYS: [Keycode; 12] = [
+  Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4,
+  Keycode::Q, Keycode::W, Keycode::E, Keycode::R,
+  Keycode::Z, Keycode::X, Keycode::C, Keycode::V,
+];
+
+// SDL events and the input they drive, polled by the gameloop and by the main loop while paused
+struct Events {
+  event_pump: EventPump,
+  controller_subsystem: GameControllerSubsystem,
+  // A pad takes the first free player slot and keeps it until it is unplugged, SDL reports the
+  // ones already plugged in as added when the event loop starts
+  gamepads: [Option<GameController>; 4],
+  key_bindings: KeyBindings,
+  pad_bindings: PadBindings,
+  players: [PlayerInput; 4],
+  input: InputState,
+  input_frame: usize,
+  scale_factor: f32,
+}
+
+impl Events {
+  fn poll(&mut self, frontend: &mut Frontend) {
+    for event in self.event_pump.poll_iter() {
+      let players = &mut self.players;
+      let mut dispatch = |player: usize, source: Source, action: Action, pressed: bool| match action {
+        Action::Command(command) => frontend.handle(command, pressed),
+        _ => players[player].set(source, action, pressed),
+      };
+
+      match event {
+        Event::Quit { .. } => dispatch(0, Source::Key, Action::Command(Command::Quit), true),
+
+        // Key repeats would toggle pause and fire the other commands again while the key is held
+        Event::KeyDown { keycode: Some(keycode), repeat: false, .. } | Event::KeyUp { keycode: Some(keycode), .. } => {
+          let pressed = matches!(event, Event::KeyDown { .. });
+          for (player, action) in self.key_bindings.get(&keycode).into_iter().flatten() {
+            dispatch(*player, Source::Key, *action, pressed);
+          }
+          if let Some(button) = POWER_PAD_KEYS.iter().position(|key| *key == keycode) {
+            self.input.power_pad = (self.input.power_pad & !(1 << button)) | ((pressed as u16) << button);
+          }
+        },
+
+        Event::ControllerDeviceAdded { which, .. } => match self.gamepads.iter().position(|slot| slot.is_none()) {
+          Some(player) => match self.controller_subsystem.open(which) {
+            Ok(gamepad) => self.gamepads[player] = Some(gamepad),
+            Err(e) => println!("Failed to open game controller {}: {}", which, e),
+          },
+          None => println!("Ignoring game controller {}, every player has one", which),
+        },
+
+        Event::ControllerDeviceRemoved { which, .. } => {
+          if let Some(player) = gamepad_player(&self.gamepads, which) {
+            self.gamepads[player] = None;
+            players[player].release(Source::Pad);
+          }
+        },
+
+        Event::ControllerButtonDown { which, button, .. } | Event::ControllerButtonUp { which, button, .. } => {
+          let pressed = matches!(event, Event::ControllerButtonDown { .. });
+          if let Some(player) = gamepad_player(&self.gamepads, which) {
+            for action in self.pad_bindings.get(&(player, button)).into_iter().flatten() {
+              dispatch(player, Source::Pad, *action, pressed);
+            }
+          }
+        },
+
+        Event::MouseMotion { x, y, .. } => {
+          self.input.mouse_x = (x as f32 / self.scale_factor) as i32;
+          self.input.mouse_y = (y as f32 / self.scale_factor) as i32;
+        },
+
+        Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } => self.input.trigger = true,
+
+        Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => self.input.trigger = false,
+        _ => {},
+      }
+    }
+  }
+
+  // Input for the next frame, turbo buttons count these frames
+  fn next_input(&mut self) -> InputState {
+    for (pad, player) in self.input.pads.iter_mut().zip(self.players.iter()) {
+      *pad = player.buttons(self.input_frame);
+    }
+    self.input_frame += 1;
+    self.input
+  }
+}
+
+fn quit(recording: Option<&RefCell<Movie>>, record_path: Option<&str>, rom_path: &str) -> ! {
+  if let (Some(movie), Some(path)) = (recording, record_path) {
+    write_movie(&movie.borrow(), path, rom_path);
+  }
+  std::process::exit(0)
+}
+
+fn disassemble_rom(path: &str) {
+  let raw_rom = std::fs::read(path).unwrap();
+  let rom = Rom::new(&raw_rom).unwrap();
+
+  let banks = rom.prg_rom.chunks(PRG_ROM_PAGE_SIZE).count();
+  for (i, bank) in rom.prg_rom.chunks(PRG_ROM_PAGE_SIZE).enumerate() {
+    // The last bank is mapped at $C000, the others are assumed to be switched in at $8000
+    let origin = if i == banks - 1 { 0xC000 } else { 0x8000 };
+    println!("; bank {} at ${:04X}", i, origin);
+    for line in disasm::disassemble(bank, origin) {
+      println!("{}", line);
+    }
+    println!();
+  }
+}
 
 fn main() {
+  let args: Vec<String> = std::env::args().collect();
+  if args.len() > 2 && args[1] == "disasm" {
+    disassemble_rom(&args[2]);
+    return;
+  }
+
   let scale_factor = 3.0;
 
   let sdl_context = sdl2::init().unwrap();
@@ -33,58 +249,208 @@ fn main() {
     .window("Tile viewer", (Frame::WIDTH as f32 * scale_factor) as u32, (Frame::HEIGHT as f32 * scale_factor) as u32)
     .position_centered().build().unwrap();
 
-  let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-  let mut event_pump = sdl_context.event_pump().unwrap();
+  let mut canvas = window.into_canvas().build().unwrap();
   canvas.set_scale(scale_factor, scale_factor).unwrap();
 
   let creator = canvas.texture_creator();
   let mut texture = creator.create_texture_target(PixelFormatEnum::RGB24, Frame::WIDTH as u32, Frame::HEIGHT as u32).unwrap();
 
-  let raw_rom = std::fs::read("pacman.nes").unwrap();
+  // The ROM comes first, like the disasm subcommand takes it, with options after it
+  let rom_path = match args.get(1) {
+    Some(path) if !path.starts_with("--") => path.as_str(),
+    _ => "pacman.nes",
+  };
+  let raw_rom = std::fs::read(rom_path).unwrap();
   let rom = Rom::new(&raw_rom).unwrap();
+  let rom_hash = savestate::hash(&raw_rom);
+
+  // The command line wins over the NES 2.0 header, which wins over a tag in the file name
+  let region = match arg_value(&args, "--region") {
+    Some(name) => Region::from_name(name).unwrap(),
+    None => rom.region.or_else(|| Region::from_filename(rom_path)).unwrap_or_default(),
+  };
+  let mut pacer = FramePacer::new(region.frame_rate());
+
+  let record_path = arg_value(&args, "--record").map(String::from);
+  let recording = record_path.as_ref().map(|_| Rc::new(RefCell::new(Movie::new(rom_hash, None))));
+  let gameloop_recording = recording.clone();
+  let gameloop_record_path = record_path.clone();
+
+  let playback = arg_value(&args, "--play").map(|path| {
+    let movie = read_movie(path).unwrap();
+    movie.check_rom(rom_hash).unwrap();
+    Rc::new(movie)
+  });
+  let gameloop_playback = playback.clone();
+  let mut movie_frame = 0;
 
   let mut frame = Frame::new();
 
-  let mut key_map = HashMap::new();
-  key_map.insert(Keycode::Down, JoypadButton::DOWN);
-  key_map.insert(Keycode::Up, JoypadButton::UP);
-  key_map.insert(Keycode::Right, JoypadButton::RIGHT);
-  key_map.insert(Keycode::Left, JoypadButton::LEFT);
-  key_map.insert(Keycode::Space, JoypadButton::SELECT);
-  key_map.insert(Keycode::Return, JoypadButton::START);
-  key_map.insert(Keycode::A, JoypadButton::BUTTON_A);
-  key_map.insert(Keycode::S, JoypadButton::BUTTON_B);
-
-  let mut cpu = CPU::new_with_gameloop(rom, move |ppu: &PPU, joypad: &mut Joypad| {
+  let (key_bindings, pad_bindings) = load_bindings(arg_value(&args, "--bindings")).unwrap();
+  let events = Rc::new(RefCell::new(Events {
+    event_pump: sdl_context.event_pump().unwrap(),
+    controller_subsystem: sdl_context.game_controller().unwrap(),
+    gamepads: Default::default(),
+    key_bindings,
+    pad_bindings,
+    players: [PlayerInput::default(); 4],
+    input: InputState::default(),
+    input_frame: 0,
+    scale_factor,
+  }));
+  let gameloop_events = events.clone();
+
+  let frontend = Rc::new(RefCell::new(Frontend::new()));
+  let gameloop_frontend = frontend.clone();
+
+  let mut cpu = CPU::new_with_gameloop(rom, move |ppu: &PPU, controllers: &mut Controllers| {
+    let mut frontend = gameloop_frontend.borrow_mut();
     render::render(ppu, &mut frame);
-    texture.update(None, &frame.data, 256 * 3).unwrap();
-    canvas.copy(&texture, None, None).unwrap();
-    canvas.present();
+    // Fast-forward doesn't wait at all and skips the frames that come in faster than the pacer
+    let present = if frontend.fast_forward() {
+      pacer.due()
+    } else {
+      pacer.wait();
+      true
+    };
+    if present {
+      texture.update(None, &frame.data, 256 * 3).unwrap();
+      canvas.copy(&texture, None, None).unwrap();
+      canvas.present();
+    }
 
-    for event in event_pump.poll_iter() {
-      match event {
-        Event::Quit { .. }
-        | Event::KeyDown {
-          keycode: Some(Keycode::Escape),
-          ..
-        } => std::process::exit(0),
-
-        Event::KeyDown { keycode, .. } => {
-          if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-            joypad.set_button_pressed(*key, true);
-          }
-        },
+    // Events are polled at least once per frame, and for as long as the frontend holds the frame. A
+    // queued command lets the frame go, the main loop applies it and holds the next one itself
+    let mut events = gameloop_events.borrow_mut();
+    loop {
+      events.poll(&mut frontend);
+      if frontend.quit() {
+        quit(gameloop_recording.as_deref(), gameloop_record_path.as_deref(), rom_path);
+      }
+      if frontend.has_pending() || !frontend.hold_frame() {
+        break;
+      }
+      std::thread::sleep(PAUSE_POLL_INTERVAL);
+    }
 
-        Event::KeyUp { keycode, .. } => {
-          if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-            joypad.set_button_pressed(*key, false);
-          }
-        },
-        _ => {},
+    let mut input = events.next_input();
+
+    // Movie input is applied after events so it overrides the keyboard
+    if let Some(movie) = &gameloop_recording {
+      movie.borrow_mut().record_frame(input.pads);
+    }
+    if let Some(movie) = &gameloop_playback {
+      match movie.play_frame(movie_frame) {
+        Some(pads) => input.pads = pads,
+        None if movie_frame == movie.len() => println!("Movie finished"),
+        None => {},
       }
+      movie_frame += 1;
     }
+
+    controllers.update(&input);
   });
 
-  cpu.reset();
-  cpu.run();
+  for (port, name) in ["--port1", "--port2"].iter().enumerate() {
+    if let Some(device) = arg_value(&args, name) {
+      let kind = DeviceKind::from_name(device).unwrap();
+      cpu.bus.controllers_mut().plug(port, kind.create());
+    }
+  }
+
+  if let Some(pattern) = arg_value(&args, "--ram") {
+    cpu.bus.set_ram_pattern(RamPattern::from_name(pattern).unwrap());
+  }
+  cpu.bus.set_region(region);
+  cpu.power_on();
+
+  if let Some(movie) = &recording {
+    movie.borrow_mut().start = Some(cpu.save_state());
+  }
+  if let Some(start) = playback.as_ref().and_then(|movie| movie.start.as_ref()) {
+    cpu.load_state(start).unwrap();
+  }
+
+  if args.iter().any(|arg| arg == "--debug") {
+    let mut debugger = Debugger::new();
+    cpu.run_with_callback(move |cpu| debugger.on_instruction(cpu));
+  } else if args.iter().any(|arg| arg == "--gdb") {
+    let port = arg_value(&args, "--gdb").and_then(|port| port.parse().ok()).unwrap_or(1234);
+    let mut gdb = GdbStub::listen(port).unwrap();
+    cpu.run_with_callback(move |cpu| gdb.on_instruction(cpu));
+  } else {
+    let interval = arg_value(&args, "--rewind-interval")
+      .and_then(|frames| frames.parse().ok())
+      .unwrap_or(rewind::DEFAULT_INTERVAL);
+    let budget = arg_value(&args, "--rewind-budget")
+      .and_then(|megabytes| megabytes.parse::<usize>().ok())
+      .map_or(rewind::DEFAULT_BUDGET, |megabytes| megabytes * 1024 * 1024);
+    let mut rewind = Rewind::new(interval, budget);
+
+    // Commands are applied between frames since the gameloop callback can't reach the CPU. Movies
+    // log resets and power cycles, but can't follow a jump to a loaded state or back in time
+    let movie_active = recording.is_some() || playback.is_some();
+    let apply_pending = |cpu: &mut CPU| {
+      while let Some(command) = frontend.borrow_mut().take_pending() {
+        match command {
+          Command::LoadState(_) if movie_active => println!("Loading states is disabled during a movie"),
+          Command::Reset | Command::PowerCycle if playback.is_some() => {
+            println!("Resets are disabled during movie playback");
+          },
+          _ => {
+            apply_command(cpu, command, rom_path);
+            let bits = match command {
+              Command::Reset => movie::SOFT_RESET,
+              Command::PowerCycle => movie::HARD_RESET,
+              _ => 0,
+            };
+            if let Some(movie) = &recording {
+              movie.borrow_mut().record_command(bits);
+            }
+          },
+        }
+      }
+    };
+
+    let mut checked_frame = 0;
+    loop {
+      // The gameloop lets a paused frame go when a command is queued, once it is applied the frame
+      // stays held here, with events polled the same way
+      let held = frontend.borrow().paused() && frontend.borrow().has_pending();
+      apply_pending(&mut cpu);
+      while held && frontend.borrow_mut().hold_frame() {
+        events.borrow_mut().poll(&mut frontend.borrow_mut());
+        if frontend.borrow().quit() {
+          quit(recording.as_deref(), record_path.as_deref(), rom_path);
+        }
+        apply_pending(&mut cpu);
+        std::thread::sleep(PAUSE_POLL_INTERVAL);
+      }
+      if let Some(movie) = &playback {
+        movie::apply_command(&mut cpu, movie.command(checked_frame));
+      }
+
+      if !cpu.run_frame() {
+        break;
+      }
+
+      // Movie checksums cover the CPU, so they are taken here rather than in the gameloop
+      if let Some(movie) = &recording {
+        movie.borrow_mut().record_checksum(&cpu);
+      }
+      if let Some(movie) = &playback {
+        if let Err(e) = movie.check_frame(checked_frame, &cpu) {
+          panic!("{}", e);
+        }
+        checked_frame += 1;
+      }
+
+      let rewinding = frontend.borrow().rewinding() && !movie_active;
+      if rewinding {
+        rewind.rewind(&mut cpu);
+      } else {
+        rewind.on_frame(&cpu);
+      }
+    }
+  }
 }
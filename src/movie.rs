@@ -0,0 +1,471 @@
+use crate::cpu::CPU;
+use crate::cpu::Mem;
+use crate::joypad::JoypadButton;
+use crate::savestate;
+
+// Version 3 stores every pad, version 2 checksums cover the CPU as well as the PPU
+const HEADER: &str = "nes_emulator movie 3";
+// Button order used by FM2 input logs, which matches JoypadButton bits 7 to 0
+const BUTTON_NAMES: &str = "RLDUTSBA";
+
+// FM2 command bits, applied before the frame they are logged with
+pub const SOFT_RESET: u8 = 1;
+pub const HARD_RESET: u8 = 2;
+
+pub struct Movie {
+  pub rom_hash: u64,
+  // Save state the recording starts from, or None to start from power-on
+  pub start: Option<Vec<u8>>,
+  // Buttons of players 1 to 4 for each frame
+  pub frames: Vec<[JoypadButton; 4]>,
+  // Command bits for each frame, 0 for most of them
+  pub commands: Vec<u8>,
+  // State checksum at each frame, used to detect desyncs (empty for imported FM2 movies)
+  pub checksums: Vec<u64>,
+  // Command waiting for the frame it precedes to be recorded
+  next_command: u8,
+}
+
+pub fn apply_command(cpu: &mut CPU, command: u8) {
+  if command & HARD_RESET != 0 {
+    cpu.power_on();
+  } else if command & SOFT_RESET != 0 {
+    cpu.reset();
+  }
+}
+
+// Covers what the game can see: CPU registers and RAM along with PPU memory. Timing counters are
+// left out so only a real divergence counts as a desync
+pub fn state_checksum(cpu: &CPU) -> u64 {
+  let ppu = cpu.bus.ppu();
+  let mut data = vec![
+    cpu.register_a,
+    cpu.register_x,
+    cpu.register_y,
+    cpu.status,
+    cpu.stack_pointer,
+  ];
+  data.extend_from_slice(&cpu.program_counter.to_le_bytes());
+  data.extend((0..0x800).map(|addr| cpu.mem_peek(addr)));
+  data.extend_from_slice(&ppu.vram);
+  data.extend_from_slice(&ppu.oam_data);
+  data.extend_from_slice(&ppu.palette_table);
+  savestate::hash(&data)
+}
+
+fn format_buttons(buttons: JoypadButton) -> String {
+  BUTTON_NAMES
+    .chars()
+    .enumerate()
+    .map(|(i, name)| if buttons.bits() & (0x80 >> i) != 0 { name } else { '.' })
+    .collect()
+}
+
+fn format_pads(pads: &[JoypadButton]) -> String {
+  pads.iter().map(|buttons| format_buttons(*buttons)).collect::<Vec<_>>().join("|")
+}
+
+fn parse_buttons(data: &str) -> Result<JoypadButton, String> {
+  if data.len() != BUTTON_NAMES.len() {
+    return Err(format!("Invalid input: {}", data));
+  }
+  let bits = data
+    .chars()
+    .enumerate()
+    .fold(0u8, |bits, (i, c)| if c == '.' || c == ' ' { bits } else { bits | (0x80 >> i) });
+  Ok(JoypadButton::from_bits_truncate(bits))
+}
+
+// Only resets and power cycles are supported, FM2 also has commands for disk and coin slots
+fn parse_command(data: &str) -> Option<u8> {
+  data.parse::<u8>().ok().filter(|bits| bits & !(SOFT_RESET | HARD_RESET) == 0)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(data: &str) -> Result<Vec<u8>, String> {
+  (0..data.len())
+    .step_by(2)
+    .map(|i| {
+      data
+        .get(i..i + 2)
+        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        .ok_or_else(|| "Invalid hex data".to_string())
+    })
+    .collect()
+}
+
+impl Movie {
+  pub fn new(rom_hash: u64, start: Option<Vec<u8>>) -> Self {
+    Movie {
+      rom_hash,
+      start,
+      frames: vec![],
+      commands: vec![],
+      checksums: vec![],
+      next_command: 0,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.frames.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.frames.is_empty()
+  }
+
+  // Call once per frame from the gameloop with the pads the game will see during the next frame
+  pub fn record_frame(&mut self, pads: [JoypadButton; 4]) {
+    self.frames.push(pads);
+    self.commands.push(self.next_command);
+    self.next_command = 0;
+  }
+
+  // Call after applying a reset or power cycle, before the next frame runs
+  pub fn record_command(&mut self, command: u8) {
+    self.next_command |= command;
+  }
+
+  // The gameloop only sees the PPU, so checksums are taken once the frame's instruction is done
+  pub fn record_checksum(&mut self, cpu: &CPU) {
+    self.checksums.push(state_checksum(cpu));
+  }
+
+  // Returns every pad for the frame, None once the movie is over
+  pub fn play_frame(&self, frame: usize) -> Option<[JoypadButton; 4]> {
+    self.frames.get(frame).copied()
+  }
+
+  // Command bits to apply before running the frame
+  pub fn command(&self, frame: usize) -> u8 {
+    self.commands.get(frame).copied().unwrap_or(0)
+  }
+
+  pub fn check_frame(&self, frame: usize, cpu: &CPU) -> Result<(), String> {
+    match self.checksums.get(frame) {
+      Some(expected) if *expected != state_checksum(cpu) => Err(format!("Movie desync at frame {}", frame)),
+      _ => Ok(()),
+    }
+  }
+
+  pub fn check_rom(&self, rom_hash: u64) -> Result<(), String> {
+    // FM2 movies carry no hash we can check
+    if self.rom_hash != 0 && self.rom_hash != rom_hash {
+      return Err("Movie was recorded with a different ROM".to_string());
+    }
+    Ok(())
+  }
+
+  pub fn to_text(&self) -> String {
+    let mut out = format!("{}\nrom {:016x}\n", HEADER, self.rom_hash);
+    if let Some(start) = &self.start {
+      out.push_str(&format!("start {}\n", encode_hex(start)));
+    }
+    for (i, pads) in self.frames.iter().enumerate() {
+      if self.command(i) != 0 {
+        out.push_str(&format!("command {}\n", self.command(i)));
+      }
+      match self.checksums.get(i) {
+        Some(checksum) => out.push_str(&format!("{} {:016x}\n", format_pads(pads), checksum)),
+        None => out.push_str(&format!("{}\n", format_pads(pads))),
+      }
+    }
+    out
+  }
+
+  pub fn from_text(text: &str) -> Result<Movie, String> {
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+      return Err("File is not a movie".to_string());
+    }
+
+    let mut movie = Movie::new(0, None);
+    let mut command = 0;
+    for line in lines {
+      let mut fields = line.split(' ');
+      match (fields.next(), fields.next()) {
+        (Some("rom"), Some(hash)) => {
+          movie.rom_hash = u64::from_str_radix(hash, 16).map_err(|_| "Invalid ROM hash".to_string())?;
+        },
+        (Some("start"), Some(state)) => movie.start = Some(decode_hex(state)?),
+        (Some("command"), Some(bits)) => {
+          command = parse_command(bits).ok_or_else(|| format!("Unsupported command: {}", bits))?;
+        },
+        (Some(pads), checksum) => {
+          let pads: Vec<&str> = pads.split('|').collect();
+          if pads.len() != 4 {
+            return Err(format!("Invalid input: {}", line));
+          }
+          let mut frame = [JoypadButton::empty(); 4];
+          for (buttons, data) in frame.iter_mut().zip(pads) {
+            *buttons = parse_buttons(data)?;
+          }
+          movie.frames.push(frame);
+          movie.commands.push(command);
+          command = 0;
+          if let Some(checksum) = checksum {
+            let checksum = u64::from_str_radix(checksum, 16).map_err(|_| format!("Invalid checksum: {}", checksum))?;
+            movie.checksums.push(checksum);
+          }
+        },
+        _ => {},
+      }
+    }
+    Ok(movie)
+  }
+
+  // FM2 only stores input, so the movie must start from power-on. Players 3 and 4 need a Four
+  // Score, which FM2 logs as four pad fields in place of the two ports
+  pub fn to_fm2(&self, rom_name: &str) -> String {
+    let fourscore = self.frames.iter().any(|pads| !pads[2].is_empty() || !pads[3].is_empty());
+    let mut out = String::from("version 3\nemuVersion 0\n");
+    out.push_str(&format!("romFilename {}\n", rom_name));
+    out.push_str(&format!("palFlag 0\nfourscore {}\nport0 1\nport1 1\nport2 0\n", fourscore as u8));
+    let pads = if fourscore { 4 } else { 2 };
+    for (i, frame) in self.frames.iter().enumerate() {
+      out.push_str(&format!("|{}|{}||\n", self.command(i), format_pads(&frame[..pads])));
+    }
+    out
+  }
+
+  pub fn from_fm2(text: &str) -> Result<Movie, String> {
+    let mut movie = Movie::new(0, None);
+    let mut fourscore = false;
+    // Port devices, 0 is nothing and 1 a gamepad
+    let mut ports = [0, 0];
+    for (i, line) in text.lines().enumerate() {
+      if !line.starts_with('|') {
+        match line.split_once(' ') {
+          Some(("fourscore", value)) => fourscore = value == "1",
+          Some(("port0", value)) => ports[0] = value.parse::<u8>().unwrap_or(u8::MAX),
+          Some(("port1", value)) => ports[1] = value.parse::<u8>().unwrap_or(u8::MAX),
+          _ => {},
+        }
+        continue;
+      }
+      if !fourscore && ports.iter().any(|port| *port > 1) {
+        return Err("Unsupported FM2 port device".to_string());
+      }
+      let fields: Vec<&str> = line.split('|').collect();
+      if fields.len() < 3 {
+        return Err(format!("Invalid FM2 input on line {}", i + 1));
+      }
+      let command = parse_command(fields[1]).ok_or_else(|| format!("Unsupported FM2 command on line {}", i + 1))?;
+      // Pads that aren't plugged in have empty fields and stay released
+      let pads = if fourscore { 4 } else { 2 };
+      let mut frame = [JoypadButton::empty(); 4];
+      for (pad, buttons) in frame.iter_mut().enumerate().take(pads) {
+        match fields.get(2 + pad) {
+          Some(data) if fourscore || ports[pad] == 1 => *buttons = parse_buttons(data)?,
+          _ => {},
+        }
+      }
+      movie.frames.push(frame);
+      movie.commands.push(command);
+    }
+    Ok(movie)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::ppu::PPU;
+  use crate::controller::Controllers;
+  use crate::controller::InputState;
+  use crate::rom::test::test_rom;
+  use std::cell::RefCell;
+  use std::rc::Rc;
+
+  fn test_movie() -> Movie {
+    let mut movie = Movie::new(0x1234, Some(vec![1, 2, 0xff]));
+    let none = JoypadButton::empty();
+    movie.frames = vec![
+      [JoypadButton::BUTTON_A | JoypadButton::RIGHT, none, none, none],
+      [none, JoypadButton::SELECT, none, none],
+      [JoypadButton::START, none, none, none],
+    ];
+    movie.checksums = vec![1, 2, 3];
+    movie
+  }
+
+  #[test]
+  fn test_text_round_trip() {
+    let movie = test_movie();
+    let text = movie.to_text();
+    assert!(text.contains("\nR......A|........|........|........ 0000000000000001\n"));
+
+    let parsed = Movie::from_text(&text).unwrap();
+    assert_eq!(parsed.rom_hash, movie.rom_hash);
+    assert_eq!(parsed.start, movie.start);
+    assert_eq!(parsed.frames, movie.frames);
+    assert_eq!(parsed.checksums, movie.checksums);
+  }
+
+  #[test]
+  fn test_fm2() {
+    let movie = test_movie();
+    let fm2 = movie.to_fm2("test.nes");
+    assert!(fm2.contains("romFilename test.nes\n"));
+    assert!(fm2.contains("fourscore 0\nport0 1\nport1 1\n"));
+    assert!(fm2.contains("|0|R......A|........||\n|0|........|.....S..||\n|0|....T...|........||\n"));
+
+    let parsed = Movie::from_fm2(&fm2).unwrap();
+    assert_eq!(parsed.frames, movie.frames);
+    assert!(parsed.checksums.is_empty());
+    assert!(parsed.start.is_none());
+
+    assert!(Movie::from_fm2("|4|........|||").is_err());
+    assert!(Movie::from_fm2("port0 2\n|0|||").is_err());
+  }
+
+  #[test]
+  fn test_fm2_ports() {
+    let mut movie = test_movie();
+    movie.frames[1][3] = JoypadButton::UP;
+    let fm2 = movie.to_fm2("test.nes");
+    assert!(fm2.contains("fourscore 1\n"));
+    assert!(fm2.contains("|0|........|.....S..|........|...U....||\n"));
+    assert_eq!(Movie::from_fm2(&fm2).unwrap().frames, movie.frames);
+
+    // Only port 0 has a pad, so anything logged for port 1 stays released
+    let parsed = Movie::from_fm2("port0 1\nport1 0\n|0|R......A|.......A||").unwrap();
+    assert_eq!(parsed.frames, vec![[JoypadButton::RIGHT | JoypadButton::BUTTON_A, JoypadButton::empty(), JoypadButton::empty(), JoypadButton::empty()]]);
+  }
+
+  // Records the A button for every even frame into VRAM through $2007, counting frames in $20
+  fn movie_cpu<'a, F>(gameloop: F) -> CPU<'a>
+  where
+    F: FnMut(&PPU, &mut Controllers) + 'a,
+  {
+    // Reset restarts the program, which turns NMI back on
+    let mut rom = test_rom();
+    rom.prg_rom[0x7ffc..0x7ffe].copy_from_slice(&[0x00, 0x06]);
+    let mut cpu = CPU::new_with_gameloop(rom, gameloop);
+    cpu.power_on();
+    // NMI handler at the test rom's vector: INC $20, RTI
+    cpu.mem_write_u16(0x0101, 0x20e6);
+    cpu.mem_write(0x0103, 0x40);
+    cpu.load(vec![
+      0xa9, 0x80, 0x8d, 0x00, 0x20, 0xa9, 0x20, 0x8d, 0x06, 0x20, 0xa9, 0x00, 0x8d, 0x06, 0x20,
+      0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, 0xad, 0x16, 0x40, 0x8d, 0x07, 0x20,
+      0xa5, 0x20, 0xc9, 0x05, 0xd0, 0xea, 0x00,
+    ]);
+    cpu.program_counter = 0x600;
+    cpu
+  }
+
+  fn play(movie: Movie) -> Result<Vec<u8>, String> {
+    let movie = Rc::new(movie);
+    let mut gameloop_frame = 0;
+    let gameloop_movie = movie.clone();
+
+    let mut input = InputState::default();
+    let mut cpu = movie_cpu(move |_: &PPU, controllers: &mut Controllers| {
+      if let Some(pads) = gameloop_movie.play_frame(gameloop_frame) {
+        input.pads = pads;
+        controllers.update(&input);
+      }
+      gameloop_frame += 1;
+    });
+    cpu.load_state(movie.start.as_ref().unwrap()).unwrap();
+
+    let mut frame = 0;
+    loop {
+      apply_command(&mut cpu, movie.command(frame));
+      if !cpu.run_frame() {
+        break;
+      }
+      movie.check_frame(frame, &cpu)?;
+      frame += 1;
+    }
+    Ok(cpu.save_state())
+  }
+
+  #[test]
+  fn test_record_and_play() {
+    let movie = Rc::new(RefCell::new(Movie::new(0, None)));
+    let gameloop_movie = movie.clone();
+
+    let mut input = InputState::default();
+    let mut cpu = movie_cpu(move |_: &PPU, controllers: &mut Controllers| {
+      let mut movie = gameloop_movie.borrow_mut();
+      input.pads[0].set(JoypadButton::BUTTON_A, movie.len().is_multiple_of(2));
+      controllers.update(&input);
+      movie.record_frame(input.pads);
+    });
+    movie.borrow_mut().start = Some(cpu.save_state());
+    while cpu.run_frame() {
+      movie.borrow_mut().record_checksum(&cpu);
+    }
+    let expected = cpu.save_state();
+    drop(cpu);
+
+    let movie = Rc::try_unwrap(movie).ok().unwrap().into_inner();
+    assert_eq!(movie.len(), 5);
+    assert_eq!(movie.checksums.len(), 5);
+    let text = movie.to_text();
+
+    assert_eq!(play(Movie::from_text(&text).unwrap()), Ok(expected));
+
+    let mut desynced = Movie::from_text(&text).unwrap();
+    desynced.frames[1][0] = JoypadButton::BUTTON_A;
+    assert_eq!(play(desynced), Err("Movie desync at frame 2".to_string()));
+  }
+
+  #[test]
+  fn test_record_reset() {
+    let movie = Rc::new(RefCell::new(Movie::new(0, None)));
+    let gameloop_movie = movie.clone();
+
+    let mut input = InputState::default();
+    let mut cpu = movie_cpu(move |_: &PPU, controllers: &mut Controllers| {
+      let mut movie = gameloop_movie.borrow_mut();
+      input.pads[1].set(JoypadButton::START, movie.len() == 1);
+      controllers.update(&input);
+      movie.record_frame(input.pads);
+    });
+    movie.borrow_mut().start = Some(cpu.save_state());
+    loop {
+      if movie.borrow().len() == 2 {
+        apply_command(&mut cpu, SOFT_RESET);
+        movie.borrow_mut().record_command(SOFT_RESET);
+      }
+      if !cpu.run_frame() {
+        break;
+      }
+      movie.borrow_mut().record_checksum(&cpu);
+    }
+    let expected = cpu.save_state();
+    drop(cpu);
+
+    let movie = Rc::try_unwrap(movie).ok().unwrap().into_inner();
+    assert_eq!(movie.commands, vec![0, 0, SOFT_RESET, 0]);
+    let text = movie.to_text();
+    assert!(text.contains("\ncommand 1\n"));
+    assert!(movie.to_fm2("test.nes").contains("\n|1|"));
+
+    assert_eq!(play(Movie::from_text(&text).unwrap()), Ok(expected));
+
+    let mut without_reset = Movie::from_text(&text).unwrap();
+    without_reset.commands[2] = 0;
+    assert_eq!(play(without_reset), Err("Movie desync at frame 2".to_string()));
+  }
+
+  #[test]
+  fn test_state_checksum_covers_cpu() {
+    let mut cpu = movie_cpu(|_: &PPU, _: &mut Controllers| {});
+    let checksum = state_checksum(&cpu);
+
+    cpu.mem_write(0x0300, 1);
+    assert_ne!(state_checksum(&cpu), checksum);
+    cpu.mem_write(0x0300, 0);
+    assert_eq!(state_checksum(&cpu), checksum);
+
+    cpu.register_x = 1;
+    assert_ne!(state_checksum(&cpu), checksum);
+  }
+}
@@ -0,0 +1,162 @@
+use crate::controller::DeviceKind;
+use crate::controller::InputState;
+use crate::cpu::CPU;
+use crate::joypad::JoypadButton;
+use crate::render;
+use crate::render::frame::Frame;
+use crate::rom::Rom;
+
+// The whole console behind a small API for hosts that drive it frame by frame
+pub struct Nes {
+  cpu: CPU<'static>,
+  input: InputState,
+  frame: Frame,
+  frames: usize,
+  // There is no APU yet, this stays empty so hosts can already wire up audio
+  audio: Vec<f32>,
+}
+
+impl Nes {
+  pub fn new(rom: Rom) -> Self {
+    let mut cpu = CPU::new(rom);
+    cpu.power_on();
+    Nes {
+      cpu,
+      input: InputState::default(),
+      frame: Frame::new(),
+      frames: 0,
+      audio: vec![],
+    }
+  }
+
+  pub fn from_bytes(raw: &[u8]) -> Result<Self, String> {
+    Ok(Nes::new(Rom::new(&raw.to_vec())?))
+  }
+
+  // Runs until the PPU reaches vblank and renders the finished picture
+  pub fn step_frame(&mut self) -> Result<(), String> {
+    if !self.cpu.run_frame() {
+      if self.cpu.jammed() {
+        return Err(format!("CPU jammed at ${:04X}", self.cpu.program_counter));
+      }
+      return Err(format!("CPU halted on BRK at ${:04X}", self.cpu.program_counter.wrapping_sub(1)));
+    }
+
+    render::render(self.cpu.bus.ppu(), &mut self.frame);
+    self.frames += 1;
+    Ok(())
+  }
+
+  pub fn set_input(&mut self, input: &InputState) {
+    self.input = *input;
+    self.cpu.bus.controllers_mut().update(&self.input);
+  }
+
+  pub fn set_buttons(&mut self, player: usize, buttons: JoypadButton) {
+    let mut input = self.input;
+    input.pads[player] = buttons;
+    self.set_input(&input);
+  }
+
+  pub fn plug(&mut self, port: usize, kind: DeviceKind) {
+    self.cpu.bus.controllers_mut().plug(port, kind.create());
+    self.cpu.bus.controllers_mut().update(&self.input);
+  }
+
+  pub fn frame(&self) -> &Frame {
+    &self.frame
+  }
+
+  pub fn audio(&self) -> &[f32] {
+    &self.audio
+  }
+
+  pub fn frame_count(&self) -> usize {
+    self.frames
+  }
+
+  pub fn save_state(&self) -> Vec<u8> {
+    self.cpu.save_state()
+  }
+
+  pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+    self.cpu.load_state(data)
+  }
+
+  pub fn reset(&mut self) {
+    self.cpu.reset();
+  }
+
+  pub fn power_cycle(&mut self) {
+    self.cpu.power_on();
+  }
+
+  pub fn cpu(&self) -> &CPU<'static> {
+    &self.cpu
+  }
+
+  pub fn cpu_mut(&mut self) -> &mut CPU<'static> {
+    &mut self.cpu
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::cpu::Mem;
+  use crate::cpu::CpuBus;
+  use crate::rom::test::test_rom;
+
+  // Counts loop iterations in $10: INC $10, JMP $0600
+  fn test_nes() -> Nes {
+    let mut nes = Nes::new(test_rom());
+    nes.cpu_mut().load(vec![0xe6, 0x10, 0x4c, 0x00, 0x06]);
+    nes.cpu_mut().program_counter = 0x600;
+    nes
+  }
+
+  #[test]
+  fn test_step_frame() {
+    let mut nes = test_nes();
+    nes.step_frame().unwrap();
+    assert_eq!(nes.frame_count(), 1);
+    assert_eq!(nes.cpu().bus.ppu().scanline(), 241);
+
+    let cycles = nes.cpu().bus.cycles();
+    nes.step_frame().unwrap();
+    // 262 scanlines of 341 dots, give or take the instruction that crossed into vblank
+    let frame_cycles = nes.cpu().bus.cycles() - cycles;
+    assert!((29774..=29788).contains(&frame_cycles));
+    assert!(nes.audio().is_empty());
+  }
+
+  #[test]
+  fn test_snapshot_and_halt() {
+    let mut nes = test_nes();
+    nes.step_frame().unwrap();
+    let state = nes.save_state();
+    let count = nes.cpu().mem_peek(0x10);
+
+    nes.step_frame().unwrap();
+    assert_ne!(nes.cpu().mem_peek(0x10), count);
+    nes.load_state(&state).unwrap();
+    assert_eq!(nes.cpu().mem_peek(0x10), count);
+
+    nes.cpu_mut().mem_write(0x600, 0x00);
+    assert_eq!(nes.step_frame(), Err("CPU halted on BRK at $0600".to_string()));
+
+    nes.reset();
+    nes.cpu_mut().program_counter = 0x600;
+    nes.cpu_mut().mem_write(0x600, 0x02);
+    assert_eq!(nes.step_frame(), Err("CPU jammed at $0600".to_string()));
+  }
+
+  #[test]
+  fn test_set_buttons() {
+    let mut nes = test_nes();
+    nes.set_buttons(1, JoypadButton::BUTTON_A);
+    nes.cpu_mut().mem_write(0x4016, 1);
+    assert_eq!(nes.cpu_mut().mem_read(0x4017) & 1, 1);
+    assert_eq!(nes.cpu_mut().mem_read(0x4016) & 1, 0);
+  }
+}
@@ -9,7 +9,7 @@ pub struct Op {
   pub mode: AddressingMode,
 }
 
-static OPS: [Op; 227] = [
+static OPS: [Op; 256] = [
   Op {code: 0x00, ins: "BRK", len: 1, cycles: 7, mode: AddressingMode::NoneAddressing},
 
   Op {code: 0x69, ins: "ADC", len: 2, cycles: 2, mode: AddressingMode::Immediate},
@@ -135,6 +135,10 @@ static OPS: [Op; 227] = [
   Op {code: 0xda, ins: "*NOP", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
   Op {code: 0xfa, ins: "*NOP", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
   Op {code: 0x80, ins: "*NOP", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0x82, ins: "*NOP", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0x89, ins: "*NOP", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0xc2, ins: "*NOP", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0xe2, ins: "*NOP", len: 2, cycles: 2, mode: AddressingMode::Immediate},
   Op {code: 0x04, ins: "*NOP", len: 2, cycles: 3, mode: AddressingMode::ZeroPage},
   Op {code: 0x44, ins: "*NOP", len: 2, cycles: 3, mode: AddressingMode::ZeroPage},
   Op {code: 0x64, ins: "*NOP", len: 2, cycles: 3, mode: AddressingMode::ZeroPage},
@@ -277,6 +281,34 @@ static OPS: [Op; 227] = [
   Op {code: 0x77, ins: "*RRA", len: 2, cycles: 6, mode: AddressingMode::ZeroPage_X},
   Op {code: 0x7b, ins: "*RRA", len: 3, cycles: 7, mode: AddressingMode::Absolute_Y},
   Op {code: 0x7f, ins: "*RRA", len: 3, cycles: 7, mode: AddressingMode::Absolute_X},
+
+  Op {code: 0x0b, ins: "*ANC", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0x2b, ins: "*ANC", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0x4b, ins: "*ALR", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0x6b, ins: "*ARR", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0xcb, ins: "*AXS", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0x8b, ins: "*XAA", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0xab, ins: "*LXA", len: 2, cycles: 2, mode: AddressingMode::Immediate},
+  Op {code: 0xbb, ins: "*LAS", len: 3, cycles: 4 /*+1 if page crossed*/, mode: AddressingMode::Absolute_Y},
+
+  Op {code: 0x9c, ins: "*SHY", len: 3, cycles: 5, mode: AddressingMode::Absolute_X},
+  Op {code: 0x9e, ins: "*SHX", len: 3, cycles: 5, mode: AddressingMode::Absolute_Y},
+  Op {code: 0x9b, ins: "*TAS", len: 3, cycles: 5, mode: AddressingMode::Absolute_Y},
+  Op {code: 0x9f, ins: "*AHX", len: 3, cycles: 5, mode: AddressingMode::Absolute_Y},
+  Op {code: 0x93, ins: "*AHX", len: 2, cycles: 6, mode: AddressingMode::Indirect_Y},
+
+  Op {code: 0x02, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0x12, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0x22, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0x32, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0x42, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0x52, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0x62, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0x72, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0x92, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0xb2, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0xd2, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
+  Op {code: 0xf2, ins: "*KIL", len: 1, cycles: 2, mode: AddressingMode::NoneAddressing},
 ];
 
 lazy_static! {
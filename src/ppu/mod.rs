@@ -1,11 +1,18 @@
 pub mod registers;
 
+use crate::bus::RamPattern;
+use crate::region::Region;
 use crate::rom::Mirroring;
 use registers::address::AddrRegister;
 use registers::control::ControlRegister;
 use registers::mask::MaskRegister;
 use registers::scroll::ScrollRegister;
 use registers::status::StatusRegister;
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
+const DOTS_PER_SCANLINE: usize = 341;
 
 pub struct PPU {
   pub chr_rom: Vec<u8>,
@@ -24,6 +31,11 @@ pub struct PPU {
   cycles: usize,
   scanline: u16,
   nmi_interrupt: Option<bool>,
+  // Set by a $2002 read right before vblank starts, which keeps the flag from being set that frame
+  vblank_suppressed: bool,
+  // Frames completed, counted when vblank starts
+  frames: usize,
+  region: Region,
 }
 
 impl PPU {
@@ -39,6 +51,9 @@ impl PPU {
       cycles: 0,
       scanline: 0,
       nmi_interrupt: None,
+      vblank_suppressed: false,
+      frames: 0,
+      region: Region::Ntsc,
 
       address: AddrRegister::new(),
       control: ControlRegister::new(),
@@ -52,6 +67,40 @@ impl PPU {
     PPU::new(vec![0; 2048], Mirroring::HORIZONTAL)
   }
 
+  // Clears the registers and refills the PPU's own memory, palette RAM comes up as zeros here
+  pub fn power_on(&mut self, pattern: RamPattern) {
+    pattern.fill(&mut self.vram);
+    pattern.fill(&mut self.oam_data);
+    self.palette_table = [0; 32];
+    self.oam_addr = 0;
+    self.address = AddrRegister::new();
+    self.status = StatusRegister::new();
+    self.cycles = 0;
+    self.scanline = 0;
+    self.nmi_interrupt = None;
+    self.vblank_suppressed = false;
+    self.frames = 0;
+    self.reset();
+  }
+
+  // Reset clears PPUCTRL, PPUMASK, the scroll latch and the read buffer, memory is kept
+  pub fn reset(&mut self) {
+    self.control = ControlRegister::new();
+    self.mask = MaskRegister::new();
+    self.scroll = ScrollRegister::new();
+    self.address.reset_latch();
+    self.internal_data_buf = 0;
+  }
+
+  pub fn set_region(&mut self, region: Region) {
+    self.region = region;
+  }
+
+  // The last scanline of the frame, vblank ends at its dot 1
+  fn pre_render_scanline(&self) -> u16 {
+    self.region.scanlines() - 1
+  }
+
   fn increment_vram_addr(&mut self) {
     self.address.increment(self.control.vram_addr_increment());
   }
@@ -90,6 +139,17 @@ impl PPU {
     }
   }
 
+  // What read_data would return, without filling the buffer or incrementing the address
+  pub fn peek_data(&self) -> u8 {
+    let addr = self.address.get();
+
+    match addr {
+      0..=0x3eff => self.internal_data_buf,
+      0x3f00..=0x3fff => self.palette_table[(addr & 0x1f) as usize],
+      _ => panic!("unexpected access to mirrored space {:x}", addr),
+    }
+  }
+
   pub fn write_to_ppu_addr(&mut self, value: u8) {
     self.address.update(value);
   }
@@ -111,6 +171,16 @@ impl PPU {
   }
 
   pub fn read_status(&mut self) -> u8 {
+    // Reading a dot before vblank starts reads it clear and the flag isn't set this frame.
+    // Reading as it starts, or a dot later, still sees it set but cancels the NMI
+    if self.scanline == self.region.vblank_scanline() {
+      match self.cycles {
+        0 => self.vblank_suppressed = true,
+        1 | 2 => self.nmi_interrupt = None,
+        _ => {},
+      }
+    }
+
     let data = self.status.bits();
 
     self.status.set_vblank(false);
@@ -119,6 +189,10 @@ impl PPU {
     data
   }
 
+  pub fn peek_status(&self) -> u8 {
+    self.status.bits()
+  }
+
   pub fn write_to_data(&mut self, data: u8) {
     let addr = self.address.get();
 
@@ -151,26 +225,57 @@ impl PPU {
     }
   }
 
-  pub fn tick(&mut self, cycles: u8) {
-    self.cycles += cycles as usize;
-    if self.cycles >= 341 {
-      self.cycles = self.cycles - 341;
+  pub fn tick(&mut self, cycles: usize) {
+    for _ in 0..cycles {
+      self.step_dot();
+    }
+  }
+
+  // Advances one dot, scanline and dot always name the dot that was just run
+  fn step_dot(&mut self) {
+    self.cycles += 1;
+    if self.cycles >= self.scanline_length() {
+      self.cycles = 0;
       self.scanline += 1;
+      if self.scanline >= self.region.scanlines() {
+        self.scanline = 0;
+      }
+    }
 
-      if self.scanline == 241 {
+    if self.cycles != 1 {
+      return;
+    }
+
+    if self.scanline == self.region.vblank_scanline() {
+      self.frames += 1;
+      if !self.vblank_suppressed {
         self.status.set_vblank(true);
         if self.control.should_generate_vblank_nmi() {
           self.nmi_interrupt = Some(true);
         }
       }
+    } else if self.scanline == self.pre_render_scanline() {
+      self.status.set_vblank(false);
+      self.status.set_sprite_zero_hit(false);
+      self.status.set_sprite_overflow(false);
+      self.vblank_suppressed = false;
+    }
+  }
 
-      if self.scanline >= 262 {
-        self.scanline = 0;
-        self.status.set_vblank(false);
-      }
+  // With rendering on, the pre-render line of every odd frame skips its last dot on NTSC
+  fn scanline_length(&self) -> usize {
+    let odd_frame = self.frames % 2 == 1;
+    if self.region.odd_frame_skip() && self.scanline == self.pre_render_scanline() && odd_frame && self.mask.rendering_enabled() {
+      DOTS_PER_SCANLINE - 1
+    } else {
+      DOTS_PER_SCANLINE
     }
   }
 
+  pub fn frame_count(&self) -> usize {
+    self.frames
+  }
+
   pub fn poll_nmi_interrupt(&mut self) -> Option<bool> {
     self.nmi_interrupt.take()
   }
@@ -182,6 +287,53 @@ impl PPU {
   pub fn nmi_interrupt_ready(&mut self) -> bool {
     self.nmi_interrupt.is_some()
   }
+
+  pub fn scanline(&self) -> u16 {
+    self.scanline
+  }
+
+  pub fn dot(&self) -> usize {
+    self.cycles
+  }
+}
+
+impl Snapshot for PPU {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_bytes(&self.palette_table);
+    w.write_bytes(&self.vram);
+    w.write_u8(self.oam_addr);
+    w.write_bytes(&self.oam_data);
+    self.address.save(w);
+    self.control.save(w);
+    self.mask.save(w);
+    self.scroll.save(w);
+    self.status.save(w);
+    w.write_u8(self.internal_data_buf);
+    w.write_u64(self.cycles as u64);
+    w.write_u16(self.scanline);
+    w.write_bool(self.nmi_interrupt.is_some());
+    w.write_bool(self.vblank_suppressed);
+    w.write_u64(self.frames as u64);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    r.read_bytes(&mut self.palette_table)?;
+    r.read_bytes(&mut self.vram)?;
+    self.oam_addr = r.read_u8()?;
+    r.read_bytes(&mut self.oam_data)?;
+    self.address.load(r)?;
+    self.control.load(r)?;
+    self.mask.load(r)?;
+    self.scroll.load(r)?;
+    self.status.load(r)?;
+    self.internal_data_buf = r.read_u8()?;
+    self.cycles = r.read_u64()? as usize;
+    self.scanline = r.read_u16()?;
+    self.nmi_interrupt = if r.read_bool()? { Some(true) } else { None };
+    self.vblank_suppressed = r.read_bool()?;
+    self.frames = r.read_u64()? as usize;
+    Ok(())
+  }
 }
 
 #[cfg(test)]
@@ -348,6 +500,30 @@ pub mod test {
       assert_eq!(ppu.status.bits() >> 7, 0);
   }
 
+  #[test]
+  fn test_peek_status_keeps_vblank() {
+      let mut ppu = PPU::new_empty_rom();
+      ppu.status.set_vblank(true);
+
+      assert_eq!(ppu.peek_status() >> 7, 1);
+      assert_eq!(ppu.read_status() >> 7, 1);
+      assert_eq!(ppu.peek_status() >> 7, 0);
+  }
+
+  #[test]
+  fn test_peek_data_keeps_address() {
+      let mut ppu = PPU::new_empty_rom();
+      ppu.vram[0x0305] = 0x66;
+
+      ppu.write_to_ppu_addr(0x23);
+      ppu.write_to_ppu_addr(0x05);
+
+      ppu.read_data(); // load buffer
+      assert_eq!(ppu.peek_data(), 0x66);
+      assert_eq!(ppu.address.get(), 0x2306);
+      assert_eq!(ppu.read_data(), 0x66);
+  }
+
   #[test]
   fn test_oam_read_write() {
       let mut ppu = PPU::new_empty_rom();
@@ -382,4 +558,105 @@ pub mod test {
       ppu.write_to_oam_addr(0x11);
       assert_eq!(ppu.read_oam_data(), 0x66);
   }
+
+  fn tick_to(ppu: &mut PPU, scanline: u16, dot: usize) {
+      while ppu.scanline() != scanline || ppu.dot() != dot {
+          ppu.tick(1);
+      }
+  }
+
+  #[test]
+  fn test_vblank_dots() {
+      let mut ppu = PPU::new_empty_rom();
+      ppu.write_to_control(0b1000_0000);
+
+      tick_to(&mut ppu, 241, 0);
+      assert!(!ppu.status.in_vblank());
+      ppu.tick(1);
+      assert!(ppu.status.in_vblank());
+      assert!(ppu.nmi_interrupt_ready());
+
+      tick_to(&mut ppu, 261, 0);
+      assert!(ppu.status.in_vblank());
+      ppu.tick(1);
+      assert!(!ppu.status.in_vblank());
+  }
+
+  #[test]
+  fn test_read_status_vblank_race() {
+      let mut ppu = PPU::new_empty_rom();
+      ppu.write_to_control(0b1000_0000);
+
+      // A dot early, the flag reads clear and stays clear for the frame
+      tick_to(&mut ppu, 241, 0);
+      assert_eq!(ppu.read_status() >> 7, 0);
+      tick_to(&mut ppu, 241, 10);
+      assert!(!ppu.status.in_vblank());
+      assert!(!ppu.nmi_interrupt_ready());
+
+      // On the dot it is set, the flag reads set but the NMI is cancelled
+      tick_to(&mut ppu, 241, 1);
+      assert!(ppu.nmi_interrupt_ready());
+      assert_eq!(ppu.read_status() >> 7, 1);
+      assert!(!ppu.nmi_interrupt_ready());
+  }
+
+  fn dots_to_next_frame(ppu: &mut PPU) -> usize {
+      let frame = ppu.frame_count();
+      let mut dots = 0;
+      while ppu.frame_count() == frame {
+          ppu.tick(1);
+          dots += 1;
+      }
+      dots
+  }
+
+  #[test]
+  fn test_odd_frame_skip() {
+      let mut ppu = PPU::new_empty_rom();
+      tick_to(&mut ppu, 241, 1);
+      assert_eq!(ppu.frame_count(), 1);
+      assert_eq!(dots_to_next_frame(&mut ppu), 341 * 262);
+      assert_eq!(dots_to_next_frame(&mut ppu), 341 * 262);
+
+      ppu.write_to_mask(0b0000_1000);
+      let odd = ppu.frame_count() % 2 == 1;
+      let first = dots_to_next_frame(&mut ppu);
+      let second = dots_to_next_frame(&mut ppu);
+      assert_eq!(first + second, 2 * 341 * 262 - 1);
+      assert_eq!(first == 341 * 262 - 1, odd);
+  }
+
+  #[test]
+  fn test_region_frames() {
+      let mut ppu = PPU::new_empty_rom();
+      ppu.set_region(Region::Pal);
+      ppu.write_to_mask(0b0000_1000);
+      tick_to(&mut ppu, 241, 1);
+      assert!(ppu.status.in_vblank());
+      assert_eq!(dots_to_next_frame(&mut ppu), 341 * 312);
+      assert_eq!(dots_to_next_frame(&mut ppu), 341 * 312);
+
+      let mut ppu = PPU::new_empty_rom();
+      ppu.set_region(Region::Dendy);
+      tick_to(&mut ppu, 291, 0);
+      assert!(!ppu.status.in_vblank());
+      ppu.tick(1);
+      assert!(ppu.status.in_vblank());
+      tick_to(&mut ppu, 311, 1);
+      assert!(!ppu.status.in_vblank());
+  }
+
+  #[test]
+  fn test_tick_across_frames() {
+      let mut ppu = PPU::new_empty_rom();
+      ppu.tick(341 * 262 * 3 + 341 * 10 + 5);
+      assert_eq!((ppu.scanline(), ppu.dot()), (10, 5));
+      assert_eq!(ppu.frame_count(), 3);
+
+      ppu.tick(341 * 231);
+      assert_eq!((ppu.scanline(), ppu.dot()), (241, 5));
+      assert!(ppu.status.in_vblank());
+      assert_eq!(ppu.frame_count(), 4);
+  }
 }
@@ -1,3 +1,7 @@
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
 pub struct AddrRegister {
   value: (u8, u8),
   hi_ptr: bool,
@@ -52,4 +56,18 @@ impl AddrRegister {
   pub fn get(&self) -> u16 {
     (self.value.0 as u16) << 8 | self.value.1 as u16
   }
-}
\ No newline at end of file
+}
+
+impl Snapshot for AddrRegister {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_u8(self.value.0);
+    w.write_u8(self.value.1);
+    w.write_bool(self.hi_ptr);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    self.value = (r.read_u8()?, r.read_u8()?);
+    self.hi_ptr = r.read_bool()?;
+    Ok(())
+  }
+}
@@ -1,3 +1,7 @@
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
 bitflags! {
   // 7  bit  0
   // ---- ----
@@ -64,3 +68,14 @@ impl ControlRegister {
     self.bits = data;
   }
 }
+
+impl Snapshot for ControlRegister {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_u8(self.bits());
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    *self = ControlRegister::from_bits_truncate(r.read_u8()?);
+    Ok(())
+  }
+}
@@ -1,3 +1,7 @@
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
 bitflags! {
   // 7  bit  0
   // ---- ----
@@ -31,4 +35,19 @@ impl MaskRegister {
   pub fn update(&mut self, data: u8) {
     self.bits = data;
   }
+
+  pub fn rendering_enabled(&self) -> bool {
+    self.intersects(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES)
+  }
+}
+
+impl Snapshot for MaskRegister {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_u8(self.bits());
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    *self = MaskRegister::from_bits_truncate(r.read_u8()?);
+    Ok(())
+  }
 }
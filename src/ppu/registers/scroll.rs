@@ -1,3 +1,7 @@
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
 pub struct ScrollRegister {
   horizontal_offset: u8,
   vertical_offset: u8,
@@ -26,4 +30,19 @@ impl ScrollRegister {
   pub fn reset_latch(&mut self) {
     self.horizontal_ptr = true;
   }
-}
\ No newline at end of file
+}
+
+impl Snapshot for ScrollRegister {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_u8(self.horizontal_offset);
+    w.write_u8(self.vertical_offset);
+    w.write_bool(self.horizontal_ptr);
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    self.horizontal_offset = r.read_u8()?;
+    self.vertical_offset = r.read_u8()?;
+    self.horizontal_ptr = r.read_bool()?;
+    Ok(())
+  }
+}
@@ -1,3 +1,7 @@
+use crate::savestate::Snapshot;
+use crate::savestate::StateReader;
+use crate::savestate::StateWriter;
+
 bitflags! {
   // 7  bit  0
   // ---- ----
@@ -46,3 +50,14 @@ impl StatusRegister {
     self.contains(StatusRegister::IN_VBLANK)
   }
 }
+
+impl Snapshot for StatusRegister {
+  fn save(&self, w: &mut StateWriter) {
+    w.write_u8(self.bits());
+  }
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+    *self = StatusRegister::from_bits_truncate(r.read_u8()?);
+    Ok(())
+  }
+}
@@ -0,0 +1,88 @@
+// TV system the console was built for, it sets the PPU frame layout and the CPU clock
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Region {
+  #[default]
+  Ntsc,
+  Pal,
+  // Famiclone timing: PAL frame length with NTSC clock ratio and a late vblank
+  Dendy,
+}
+
+impl Region {
+  pub fn from_name(name: &str) -> Result<Region, String> {
+    match name {
+      "ntsc" => Ok(Region::Ntsc),
+      "pal" => Ok(Region::Pal),
+      "dendy" => Ok(Region::Dendy),
+      _ => Err(format!("Unknown region: {}", name)),
+    }
+  }
+
+  // Picks the region from No-Intro and GoodNES style tags, like "(Europe)" or "(E)"
+  pub fn from_filename(path: &str) -> Option<Region> {
+    let tags: Vec<&str> = path
+      .split(['(', ')', '[', ']'])
+      .skip(1)
+      .step_by(2)
+      .flat_map(|tag| tag.split(','))
+      .map(|tag| tag.trim())
+      .collect();
+
+    tags.iter().find_map(|tag| match *tag {
+      "Dendy" => Some(Region::Dendy),
+      "E" | "Europe" | "PAL" | "Australia" | "Germany" | "France" | "Spain" | "Italy" | "Sweden" => Some(Region::Pal),
+      "U" | "USA" | "J" | "Japan" | "NTSC" | "Korea" => Some(Region::Ntsc),
+      _ => None,
+    })
+  }
+
+  pub fn scanlines(self) -> u16 {
+    match self {
+      Region::Ntsc => 262,
+      Region::Pal | Region::Dendy => 312,
+    }
+  }
+
+  // Vblank starts at dot 1 of this scanline
+  pub fn vblank_scanline(self) -> u16 {
+    match self {
+      Region::Ntsc | Region::Pal => 241,
+      Region::Dendy => 291,
+    }
+  }
+
+  // PPU dots per CPU cycle as numerator and denominator, PAL runs 16 dots every 5 cycles
+  pub fn dots_per_cycle(self) -> (usize, usize) {
+    match self {
+      Region::Ntsc | Region::Dendy => (3, 1),
+      Region::Pal => (16, 5),
+    }
+  }
+
+  // Only the NTSC PPU shortens the pre-render line of odd frames
+  pub fn odd_frame_skip(self) -> bool {
+    self == Region::Ntsc
+  }
+
+  pub fn frame_rate(self) -> f64 {
+    match self {
+      Region::Ntsc => 60.0988,
+      Region::Pal | Region::Dendy => 50.007,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_from_filename() {
+    assert_eq!(Region::from_filename("roms/Super Mario Bros. (Europe).nes"), Some(Region::Pal));
+    assert_eq!(Region::from_filename("Contra (U) [!].nes"), Some(Region::Ntsc));
+    assert_eq!(Region::from_filename("Tetris (Japan, USA).nes"), Some(Region::Ntsc));
+    assert_eq!(Region::from_filename("Battle City (Dendy).nes"), Some(Region::Dendy));
+    assert_eq!(Region::from_filename("(E) homebrew.nes"), Some(Region::Pal));
+    assert_eq!(Region::from_filename("pacman.nes"), None);
+  }
+}
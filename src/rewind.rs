@@ -0,0 +1,238 @@
+use crate::cpu::CPU;
+use std::collections::VecDeque;
+
+pub const DEFAULT_INTERVAL: usize = 2;
+pub const DEFAULT_BUDGET: usize = 32 * 1024 * 1024;
+
+// Keeps the newest snapshot in full and every older one as a delta against the one after it,
+// so stepping back is one delta application and the oldest entries can be dropped freely
+pub struct Rewind {
+  interval: usize,
+  budget: usize,
+  frames: usize,
+  latest: Option<Vec<u8>>,
+  deltas: VecDeque<Vec<u8>>,
+  used: usize,
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+  while value >= 0x80 {
+    out.push((value as u8) | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<usize> {
+  let mut value = 0;
+  let mut shift = 0;
+  loop {
+    let byte = *data.get(*pos)?;
+    *pos += 1;
+    value |= ((byte & 0x7f) as usize) << shift;
+    if byte & 0x80 == 0 {
+      return Some(value);
+    }
+    shift += 7;
+  }
+}
+
+// Encodes target as runs of (unchanged length, changed length, changed bytes) against base
+pub fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+  let mut out = vec![];
+  write_varint(&mut out, target.len());
+
+  let same = |i: usize| base.get(i) == Some(&target[i]);
+  let mut i = 0;
+  while i < target.len() {
+    let start = i;
+    while i < target.len() && same(i) {
+      i += 1;
+    }
+    write_varint(&mut out, i - start);
+
+    let start = i;
+    while i < target.len() && !same(i) {
+      i += 1;
+    }
+    write_varint(&mut out, i - start);
+    out.extend_from_slice(&target[start..i]);
+  }
+  out
+}
+
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+  let mut pos = 0;
+  let len = read_varint(delta, &mut pos)?;
+  let mut out = Vec::with_capacity(len);
+
+  while out.len() < len {
+    let same = read_varint(delta, &mut pos)?;
+    out.extend_from_slice(base.get(out.len()..out.len() + same)?);
+
+    let changed = read_varint(delta, &mut pos)?;
+    out.extend_from_slice(delta.get(pos..pos + changed)?);
+    pos += changed;
+  }
+
+  if out.len() != len {
+    return None;
+  }
+  Some(out)
+}
+
+impl Rewind {
+  pub fn new(interval: usize, budget: usize) -> Self {
+    Rewind {
+      interval: interval.max(1),
+      budget,
+      frames: 0,
+      latest: None,
+      deltas: VecDeque::new(),
+      used: 0,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.deltas.len() + self.latest.is_some() as usize
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.latest.is_none()
+  }
+
+  pub fn memory_used(&self) -> usize {
+    self.used
+  }
+
+  // Call once per frame, takes a snapshot every `interval` frames
+  pub fn on_frame(&mut self, cpu: &CPU) {
+    self.frames += 1;
+    if self.frames >= self.interval {
+      self.frames = 0;
+      self.push(cpu.save_state());
+    }
+  }
+
+  pub fn push(&mut self, state: Vec<u8>) {
+    if let Some(latest) = self.latest.take() {
+      let delta = encode_delta(&state, &latest);
+      self.used += delta.len();
+      self.used -= latest.len();
+      self.deltas.push_back(delta);
+    }
+    self.used += state.len();
+    self.latest = Some(state);
+
+    while self.used > self.budget {
+      match self.deltas.pop_front() {
+        Some(delta) => self.used -= delta.len(),
+        None => break,
+      }
+    }
+  }
+
+  pub fn pop(&mut self) -> Option<Vec<u8>> {
+    let latest = self.latest.take()?;
+    self.used -= latest.len();
+
+    if let Some(delta) = self.deltas.pop_back() {
+      self.used -= delta.len();
+      let previous = apply_delta(&latest, &delta).expect("corrupt rewind delta");
+      self.used += previous.len();
+      self.latest = Some(previous);
+    }
+    Some(latest)
+  }
+
+  // Loads the previous snapshot, the oldest one is kept so holding rewind stops there
+  pub fn rewind(&mut self, cpu: &mut CPU) -> bool {
+    let state = match self.pop() {
+      Some(state) => state,
+      None => return false,
+    };
+    if self.latest.is_none() {
+      self.push(state.clone());
+    }
+    self.frames = 0;
+    cpu.load_state(&state).is_ok()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::cpu::Mem;
+  use crate::rom::test::test_rom;
+
+  #[test]
+  fn test_delta_round_trip() {
+    let base = vec![1, 2, 3, 4, 5, 6, 7, 8];
+    let target = vec![1, 2, 9, 4, 5, 6, 0, 0, 10];
+
+    let delta = encode_delta(&base, &target);
+    assert_eq!(delta, vec![9, 2, 1, 9, 3, 3, 0, 0, 10]);
+    assert_eq!(apply_delta(&base, &delta), Some(target.clone()));
+
+    let delta = encode_delta(&target, &base);
+    assert_eq!(apply_delta(&target, &delta), Some(base));
+
+    assert_eq!(apply_delta(&[], &[2, 1, 0]), None);
+  }
+
+  #[test]
+  fn test_push_pop() {
+    let mut rewind = Rewind::new(1, usize::MAX);
+    rewind.push(vec![0; 100]);
+    rewind.push(vec![1; 100]);
+    let mut third = vec![1; 100];
+    third[50] = 2;
+    rewind.push(third.clone());
+
+    assert_eq!(rewind.len(), 3);
+    assert!(rewind.memory_used() < 300);
+    assert_eq!(rewind.pop(), Some(third));
+    assert_eq!(rewind.pop(), Some(vec![1; 100]));
+    assert_eq!(rewind.pop(), Some(vec![0; 100]));
+    assert_eq!(rewind.pop(), None);
+    assert_eq!(rewind.memory_used(), 0);
+  }
+
+  #[test]
+  fn test_budget() {
+    let mut rewind = Rewind::new(1, 250);
+    for i in 0..10 {
+      rewind.push(vec![i; 100]);
+    }
+
+    assert!(rewind.memory_used() <= 250);
+    assert!(rewind.len() > 1);
+    let mut last = None;
+    while let Some(state) = rewind.pop() {
+      last = Some(state);
+    }
+    assert!(last.unwrap()[0] > 0);
+  }
+
+  #[test]
+  fn test_rewind_cpu() {
+    let mut cpu = CPU::new(test_rom());
+    cpu.power_on();
+    let mut rewind = Rewind::new(2, DEFAULT_BUDGET);
+
+    for i in 0..6 {
+      cpu.mem_write(0x10, i);
+      rewind.on_frame(&cpu);
+    }
+
+    assert_eq!(rewind.len(), 3);
+    assert!(rewind.rewind(&mut cpu));
+    assert_eq!(cpu.mem_read(0x10), 5);
+    assert!(rewind.rewind(&mut cpu));
+    assert_eq!(cpu.mem_read(0x10), 3);
+    assert!(rewind.rewind(&mut cpu));
+    assert_eq!(cpu.mem_read(0x10), 1);
+    assert!(rewind.rewind(&mut cpu));
+    assert_eq!(cpu.mem_read(0x10), 1);
+  }
+}
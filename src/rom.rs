@@ -1,5 +1,7 @@
+use crate::region::Region;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
-const PRG_ROM_PAGE_SIZE: usize = 16384;
+pub const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 
 #[derive(Debug, PartialEq)]
@@ -15,6 +17,8 @@ pub struct Rom {
   pub chr_rom: Vec<u8>,
   pub mapper: u8,
   pub screen_mirroring: Mirroring,
+  // Only NES 2.0 headers say reliably, None when the header doesn't or the game runs on any
+  pub region: Option<Region>,
 }
 
 impl Rom {
@@ -26,10 +30,22 @@ impl Rom {
     let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
 
     let ines_ver = (raw[7] >> 2) & 0b11;
-    if ines_ver != 0 {
-      return Err("NES2.0 format is not supported".to_string());
+    let nes2 = ines_ver == 2;
+    if ines_ver != 0 && !nes2 {
+      return Err("Unknown iNES header version".to_string());
+    }
+    // Only the iNES subset of NES 2.0 is read, larger ROM sizes aren't supported
+    if nes2 && raw[9] != 0 {
+      return Err("NES2.0 ROM size extensions are not supported".to_string());
     }
 
+    let region = match (nes2, raw[12] & 0b11) {
+      (true, 0) => Some(Region::Ntsc),
+      (true, 1) => Some(Region::Pal),
+      (true, 3) => Some(Region::Dendy),
+      _ => None,
+    };
+
     let four_screen = (raw[6] & 0b1000) != 0;
     let vertical_mirroring = (raw[6] & 0b1) != 0;
     let mirroring: Mirroring = match (four_screen, vertical_mirroring) {
@@ -50,6 +66,7 @@ impl Rom {
       chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
       mapper: mapper,
       screen_mirroring: mirroring,
+      region,
     })
   }
 }
@@ -123,11 +140,29 @@ pub mod test {
     assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
   }
 
+  #[test]
+  fn test_nes2_region() {
+    let test_rom = create_rom(TestRom {
+      header: vec![
+        0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0x08, 00, 00, 00, 00, 0x01, 00, 00, 00,
+      ],
+      trainer: None,
+      prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+      chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+    });
+
+    let rom = Rom::new(&test_rom).unwrap();
+
+    assert_eq!(rom.prg_rom, vec![1; 2 * PRG_ROM_PAGE_SIZE]);
+    assert_eq!(rom.region, Some(Region::Pal));
+    assert_eq!(self::test_rom().region, None);
+  }
+
   #[test]
   fn test_nes2_err() {
     let test_rom = create_rom(TestRom {
       header: vec![
-        0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 08, 00, 00, 00, 00, 00, 00, 00, 00,
+        0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 08, 00, 0x01, 00, 00, 00, 00, 00, 00,
       ],
       trainer: None,
       prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
@@ -138,7 +173,7 @@ pub mod test {
 
     match rom {
       Result::Ok(_) => assert!(false, "should not load rom"),
-      Result::Err(s) => assert_eq!(s, "NES2.0 format is not supported"),
+      Result::Err(s) => assert_eq!(s, "NES2.0 ROM size extensions are not supported"),
     }
   }
 
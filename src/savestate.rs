@@ -0,0 +1,149 @@
+const MAGIC: [u8; 4] = [0x4E, 0x45, 0x53, 0x53]; // "NESS"
+pub const VERSION: u8 = 8;
+
+pub trait Snapshot {
+  fn save(&self, w: &mut StateWriter);
+
+  fn load(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+pub struct StateWriter {
+  data: Vec<u8>,
+}
+
+pub struct StateReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+// FNV-1a, used to make sure a state is loaded against the ROM it was saved with
+pub fn hash(data: &[u8]) -> u64 {
+  data.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+impl StateWriter {
+  pub fn new() -> Self {
+    let mut data = Vec::with_capacity(0x1000);
+    data.extend_from_slice(&MAGIC);
+    data.push(VERSION);
+    StateWriter { data }
+  }
+
+  pub fn write_u8(&mut self, value: u8) {
+    self.data.push(value);
+  }
+
+  pub fn write_bool(&mut self, value: bool) {
+    self.data.push(value as u8);
+  }
+
+  pub fn write_u16(&mut self, value: u16) {
+    self.data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn write_u64(&mut self, value: u64) {
+    self.data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn write_bytes(&mut self, bytes: &[u8]) {
+    self.data.extend_from_slice(bytes);
+  }
+
+  pub fn finish(self) -> Vec<u8> {
+    self.data
+  }
+}
+
+impl Default for StateWriter {
+  fn default() -> Self {
+    StateWriter::new()
+  }
+}
+
+impl<'a> StateReader<'a> {
+  pub fn new(data: &'a [u8]) -> Result<Self, String> {
+    if data.len() < MAGIC.len() + 1 || data[0..4] != MAGIC {
+      return Err("Not a save state".to_string());
+    }
+    if data[4] != VERSION {
+      return Err(format!("Unsupported save state version {}", data[4]));
+    }
+    Ok(StateReader { data, pos: MAGIC.len() + 1 })
+  }
+
+  fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+    if self.pos + len > self.data.len() {
+      return Err("Save state is truncated".to_string());
+    }
+    let bytes = &self.data[self.pos..self.pos + len];
+    self.pos += len;
+    Ok(bytes)
+  }
+
+  pub fn read_u8(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  pub fn read_bool(&mut self) -> Result<bool, String> {
+    Ok(self.read_u8()? != 0)
+  }
+
+  pub fn read_u16(&mut self) -> Result<u16, String> {
+    let bytes = self.take(2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+  }
+
+  pub fn read_u64(&mut self) -> Result<u64, String> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(self.take(8)?);
+    Ok(u64::from_le_bytes(bytes))
+  }
+
+  pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), String> {
+    out.copy_from_slice(self.take(out.len())?);
+    Ok(())
+  }
+
+  pub fn finish(self) -> Result<(), String> {
+    if self.pos != self.data.len() {
+      return Err("Unexpected data at the end of save state".to_string());
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_read_write() {
+    let mut w = StateWriter::new();
+    w.write_u8(0x12);
+    w.write_bool(true);
+    w.write_u16(0x3456);
+    w.write_u64(0x789a_bcde_f012_3456);
+    w.write_bytes(&[1, 2, 3]);
+    let data = w.finish();
+
+    let mut r = StateReader::new(&data).unwrap();
+    assert_eq!(r.read_u8(), Ok(0x12));
+    assert_eq!(r.read_bool(), Ok(true));
+    assert_eq!(r.read_u16(), Ok(0x3456));
+    assert_eq!(r.read_u64(), Ok(0x789a_bcde_f012_3456));
+    let mut bytes = [0; 3];
+    r.read_bytes(&mut bytes).unwrap();
+    assert_eq!(bytes, [1, 2, 3]);
+    assert_eq!(r.read_u8(), Err("Save state is truncated".to_string()));
+    assert_eq!(r.finish(), Ok(()));
+  }
+
+  #[test]
+  fn test_header_err() {
+    assert!(StateReader::new(&[0x4E, 0x45, 0x53, 0x1A, VERSION]).is_err());
+    assert_eq!(
+      StateReader::new(&[0x4E, 0x45, 0x53, 0x53, 0xff]).err(),
+      Some("Unsupported save state version 255".to_string())
+    );
+  }
+}
@@ -1,10 +1,22 @@
 use crate::cpu::CPU;
 use crate::cpu::Mem;
+use crate::cpu::CpuBus;
 use crate::cpu::AddressingMode;
 use crate::ops::OPS_MAP;
 
-pub fn trace(cpu: &mut CPU) -> String {
-  let opcode = cpu.mem_read(cpu.program_counter);
+pub enum TraceFormat {
+  // A/X/Y/P/SP only, as in nestest_no_cycle.log
+  Registers,
+  // Registers followed by PPU scanline/dot and CPU cycle count, as in nestest.log
+  Timing,
+}
+
+pub fn trace(cpu: &CPU) -> String {
+  trace_with_format(cpu, TraceFormat::Registers)
+}
+
+pub fn trace_with_format(cpu: &CPU, format: TraceFormat) -> String {
+  let opcode = cpu.mem_peek(cpu.program_counter);
 
   let op = OPS_MAP[&opcode];
   let mut log = String::with_capacity(100);
@@ -13,7 +25,7 @@ pub fn trace(cpu: &mut CPU) -> String {
 
   let mut args: Vec<String> = vec![];
   for i in 0..op.len as u16 {
-    let arg = cpu.mem_read(cpu.program_counter + i);
+    let arg = cpu.mem_peek(cpu.program_counter + i);
     let string = format!("{:02X}", arg);
     args.push(string);
   }
@@ -29,12 +41,12 @@ pub fn trace(cpu: &mut CPU) -> String {
   let (addr, value) = match op.mode {
     AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
     _ => {
-      let addr = cpu.get_absolute_address(&op.mode, cpu.program_counter + 1);
-      (addr, cpu.mem_read(addr))
+      let addr = cpu.peek_absolute_address(&op.mode, cpu.program_counter + 1);
+      (addr, cpu.mem_peek(addr))
     }
   };
 
-  let init_addr = cpu.mem_read_u16(cpu.program_counter + 1);
+  let init_addr = cpu.mem_peek_u16(cpu.program_counter + 1);
 
   let args_assembly = match op.mode {
     AddressingMode::Immediate => {
@@ -50,7 +62,7 @@ pub fn trace(cpu: &mut CPU) -> String {
       format!(" ${},Y @ {:02X} = {:02X}", args[1], addr, value)
     },
     AddressingMode::Indirect_X => {
-      let init_addr = cpu.mem_read(cpu.program_counter + 1);
+      let init_addr = cpu.mem_peek(cpu.program_counter + 1);
       let wrap = init_addr.wrapping_add(cpu.register_x);
       format!(" (${:02X},X) @ {:02X} = {:04X} = {:02X}", init_addr, wrap, addr, value)
     },
@@ -79,7 +91,7 @@ pub fn trace(cpu: &mut CPU) -> String {
           }
         }
         2 => {
-          let offset = cpu.mem_read(cpu.program_counter + 1) as i8;
+          let offset = cpu.mem_peek(cpu.program_counter + 1) as i8;
           let addr = cpu.program_counter
           .wrapping_add(2)
           .wrapping_add(offset as u16);
@@ -89,13 +101,13 @@ pub fn trace(cpu: &mut CPU) -> String {
           match op.code {
             // jmp indirect
             0x6C => {
-              let addr = cpu.mem_read_u16(cpu.program_counter + 1);
+              let addr = cpu.mem_peek_u16(cpu.program_counter + 1);
               let indirect_addr = if addr & 0x00FF == 0x00FF {
-                let lo = cpu.mem_read(addr);
-                let hi = cpu.mem_read(addr & 0xFF00);
+                let lo = cpu.mem_peek(addr);
+                let hi = cpu.mem_peek(addr & 0xFF00);
                 (hi as u16) << 8 | (lo as u16)
               } else {
-                cpu.mem_read_u16(addr)
+                cpu.mem_peek_u16(addr)
               };
               format!(" (${:04X}) = {:04X}", addr, indirect_addr)
             }, 
@@ -118,6 +130,16 @@ pub fn trace(cpu: &mut CPU) -> String {
     cpu.stack_pointer,
   ));
 
+  if let TraceFormat::Timing = format {
+    let ppu = cpu.bus.ppu();
+    log.push_str(&format!(
+      " PPU:{:3},{:3} CYC:{}",
+      ppu.scanline(),
+      ppu.dot(),
+      cpu.bus.cycles(),
+    ));
+  }
+
   log
 }
 
@@ -132,7 +154,7 @@ mod test {
   #[test]
   fn test_format_trace() {
     let mut cpu = CPU::new(test_rom());
-    cpu.reset();
+    cpu.power_on();
 
     cpu.mem_write(100, 0xa2);
     cpu.mem_write(101, 0x01);
@@ -166,10 +188,37 @@ mod test {
     );
   }
 
+  #[test]
+  fn test_format_trace_timing() {
+    let mut cpu = CPU::new(test_rom());
+    cpu.power_on();
+
+    cpu.mem_write(100, 0xa2);
+    cpu.mem_write(101, 0x01);
+    cpu.mem_write(102, 0xca);
+    cpu.mem_write(103, 0x00);
+
+    cpu.program_counter = 100;
+
+    let mut result: Vec<String> = vec![];
+    cpu.run_with_callback(|cpu| {
+      result.push(trace_with_format(cpu, TraceFormat::Timing));
+    });
+
+    assert_eq!(
+      "0064  A2 01     LDX #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+      result[0]
+    );
+    assert_eq!(
+      "0066  CA        DEX                             A:00 X:01 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9",
+      result[1]
+    );
+  }
+
   #[test]
   fn test_format_mem_access() {
     let mut cpu = CPU::new(test_rom());
-    cpu.reset();
+    cpu.power_on();
 
     // ORA ($33), Y
     cpu.mem_write(100, 0x11);
@@ -195,4 +244,26 @@ mod test {
       result[0]
     );
   }
+
+  #[test]
+  fn test_trace_has_no_side_effects() {
+    let mut cpu = CPU::new(test_rom());
+    cpu.power_on();
+
+    // LDA $2002
+    cpu.mem_write(100, 0xad);
+    cpu.mem_write(101, 0x02);
+    cpu.mem_write(102, 0x20);
+    cpu.program_counter = 100;
+
+    while cpu.bus.ppu().peek_status() >> 7 == 0 {
+      cpu.bus.tick(1);
+    }
+
+    assert_eq!(
+      "0064  AD 02 20  LDA $2002 = 80                  A:00 X:00 Y:00 P:24 SP:FD",
+      trace(&cpu)
+    );
+    assert_eq!(cpu.bus.ppu().peek_status() >> 7, 1);
+  }
 }